@@ -0,0 +1,71 @@
+use std::{
+    f32::consts::PI,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use rodio::Source;
+
+const SAMPLE_RATE: u32 = 48_000;
+
+/// The current pitch bend in cents, shared with all sounding waves so they follow it while playing.
+#[derive(Debug, Clone, Default)]
+pub struct Bend(Arc<AtomicU32>);
+
+impl Bend {
+    pub fn set(&self, cents: f32) {
+        self.0.store(cents.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn cents(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// An infinite sine wave whose frequency follows the pitch bend.
+pub struct BentSineWave {
+    frequency: f32,
+    bend: Bend,
+    phase: f32,
+}
+
+impl BentSineWave {
+    pub fn new(frequency: f32, bend: Bend) -> Self {
+        Self {
+            frequency,
+            bend,
+            phase: 0.0,
+        }
+    }
+}
+
+impl Iterator for BentSineWave {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let frequency = morivar::notes::detune(self.frequency, self.bend.cents());
+        self.phase = (self.phase + frequency / SAMPLE_RATE as f32).fract();
+        Some((2.0 * PI * self.phase).sin())
+    }
+}
+
+impl Source for BentSineWave {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
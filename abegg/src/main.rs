@@ -6,15 +6,15 @@ use anyhow::Context;
 use clap::{command, Parser};
 use client_utils::{
     announce_as_consumer, announce_protocol_version, create_client, create_uri, create_watchdog,
+    flatten, receive_text, Identification, Redirected,
 };
-use either::Either;
 use futures_util::SinkExt;
 use klib::core::{
-    base::Playable, chord::Chord, named_pitch::NamedPitch, note::Note, octave::Octave,
+    base::Playable, chord::HasChord, named_pitch::NamedPitch, note::Note, octave::Octave,
 };
-use morivar::{ConsumerToServer, ServerToConsumer, ToMessage};
+use morivar::{ConsumerToServer, ServerToConsumer, SoundingNotes, ToMessage, TunedNote};
 use once_cell::sync::Lazy;
-use pitches::Pitches;
+use playback::Event;
 use simple_tokio_watchdog::{Expired, Signal};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
@@ -25,8 +25,9 @@ use tokio::{
 use tokio_websockets::WebsocketStream;
 use tracing::{info, warn};
 
-mod pitches;
+mod bend;
 mod playback;
+mod voices;
 
 #[derive(Debug, Parser)]
 #[command(author, version)]
@@ -70,30 +71,45 @@ async fn main() -> anyhow::Result<()> {
     let play_jingle = args.jingle;
     let args = args.args;
     let secure = args.secure;
-    let id = args.id;
+    let identification = Identification {
+        id: args.id,
+        room: args.room,
+        token: args.token,
+    };
 
     if play_jingle {
         jingle(&*ABEGG)?;
     }
 
-    let uri = create_uri(args.url, args.secure)?;
+    let mut uri = create_uri(args.url, args.secure)?;
 
     loop {
-        let uri = uri.clone();
-        let id = id.clone();
+        let connect_uri = uri.clone();
+        let identification = identification.clone();
 
-        tokio::spawn(async move {
+        // tokio::spawn to contain errors and panics, then wait, then rebuild
+        let handle = tokio::spawn(async move {
             let (chord_tx, chord_rx) = mpsc::channel(32);
 
-            let handle = spawn_blocking(move || playback::run(chord_rx));
+            let playback = spawn_blocking(move || playback::run(chord_rx));
 
             info!("Attempting to connect to server");
-            let stream = create_client(&uri, secure).await?;
+            let stream = create_client(&connect_uri, secure).await?;
 
-            abegg(stream, &id, args.pingpong, chord_tx).await?;
-            join!(handle).0?;
-            anyhow::Ok(())
+            let result = abegg(stream, &identification, args.pingpong, chord_tx).await;
+            join!(playback).0?;
+            result
         });
+
+        if let Err(e) = flatten(handle).await {
+            if let Some(Redirected(address)) = e.downcast_ref() {
+                info!("Following redirect to {address}");
+                uri = create_uri(address.clone(), secure)?;
+                continue;
+            }
+            warn!("{e:?}");
+        }
+
         tokio::time::sleep(client_utils::jittering_retry_duration()).await;
     }
 }
@@ -101,39 +117,39 @@ async fn main() -> anyhow::Result<()> {
 /// Handle the client connection
 async fn abegg<S>(
     mut stream: WebsocketStream<S>,
-    id: &str,
+    identification: &Identification,
     pingpong: bool,
-    chords: mpsc::Sender<Either<Chord, Pitches>>,
+    chords: mpsc::Sender<Event>,
 ) -> anyhow::Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    announce_protocol_version(&mut stream).await?;
+    let heartbeat = announce_protocol_version(&mut stream).await?;
+
+    announce_as_consumer(identification, &mut stream).await?;
 
-    announce_as_consumer(id, &mut stream).await?;
+    let (mut interval, watchdog, mut expiration) = create_watchdog(&heartbeat).await?;
 
-    let (mut interval, watchdog, mut expiration) = create_watchdog().await?;
+    let mut sounding = SoundingNotes::default();
 
     loop {
         select! {
-            msg = stream.next() => {
-                let Some(Ok(msg)) = msg else {
+            msg = receive_text(&mut stream) => {
+                let Some(Ok(text)) = msg else {
                     warn!("Breaking on client message: {msg:?}");
                     break;
                 };
-                let Ok(text) = msg.as_text() else {
-                    warn!("Received non-text message, stopping receive");
-                    break;
-                };
                 if pingpong {
                     // on any message, even non-pong, stop the watchdog - the server is alive at least.
                     watchdog.send(Signal::Stop).await.context("Failed to reset the watchdog")?;
                 }
-                let Ok(new_handle) = handle_message(text) else {
-                    break
+                let event = match handle_message(&text, &mut sounding) {
+                    Ok(event) => event,
+                    Err(e) if e.is::<Redirected>() => return Err(e),
+                    Err(_) => break,
                 };
-                if let Some(either) = new_handle {
-                    chords.send(either).await?;
+                if let Some(event) = event {
+                    chords.send(event).await?;
                 }
             }
             _i = interval.tick(), if pingpong => {
@@ -152,16 +168,45 @@ where
     Ok(())
 }
 
-fn handle_message(text: &str) -> anyhow::Result<Option<Either<Chord, Pitches>>> {
+fn handle_message(text: &str, sounding: &mut SoundingNotes) -> anyhow::Result<Option<Event>> {
     let Ok(msg) = serde_json::from_str::<ServerToConsumer>(text) else {
         anyhow::bail!("Protocol error, expected text message, got {text:?}")
     };
     match msg {
-        ServerToConsumer::ChordEvent(chord) => Ok(Some(Either::Left(chord))),
-        ServerToConsumer::PitchesEvent(pitches) => {
-            let pitches = Pitches::from(pitches);
-            Ok(Some(Either::Right(pitches)))
+        ServerToConsumer::ChordEvent(chord) => Ok(Some(held(chord.chord()))),
+        ServerToConsumer::PitchesEvent(pitches)
+        | ServerToConsumer::RecognizedPitchesEvent { pitches, .. } => Ok(Some(held(pitches))),
+        ServerToConsumer::TunedPitchesEvent(pitches) => Ok(Some(Event::Held(pitches))),
+        ServerToConsumer::PitchBendEvent { cents } => Ok(Some(Event::PitchBend(cents))),
+        ServerToConsumer::NotesOn(delta) => {
+            if !sounding.notes_on(&delta) {
+                warn!("Missed note events, held notes may be off until the next keyframe");
+            }
+            Ok(Some(held(sounding.notes().iter().copied())))
+        }
+        ServerToConsumer::NotesOff(delta) => {
+            if !sounding.notes_off(&delta) {
+                warn!("Missed note events, held notes may be off until the next keyframe");
+            }
+            Ok(Some(held(sounding.notes().iter().copied())))
+        }
+        ServerToConsumer::Keyframe(keyframe) => {
+            sounding.keyframe(&keyframe);
+            Ok(Some(held(sounding.notes().iter().copied())))
+        }
+        ServerToConsumer::Silence => {
+            sounding.clear();
+            Ok(Some(Event::Held(Vec::new())))
+        }
+        ServerToConsumer::Pong => Ok(None),
+        ServerToConsumer::Redirect { address } => Err(Redirected::parse(&address)?.into()),
+        ServerToConsumer::Missed { count } => {
+            warn!("Fell behind, the server skipped {count} events");
+            Ok(None)
         }
-        ServerToConsumer::Silence | ServerToConsumer::Pong => Ok(None),
     }
 }
+
+fn held(notes: impl IntoIterator<Item = Note>) -> Event {
+    Event::Held(notes.into_iter().map(TunedNote::from).collect())
+}
@@ -1,31 +1,34 @@
-use std::time::Duration;
-
-use either::Either;
-use klib::core::{base::Playable, chord::Chord};
+use morivar::TunedNote;
 use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::{bend::Bend, voices::Voices};
 
-use crate::pitches::Pitches;
+pub enum Event {
+    /// The complete set of sounding notes, which ring until they are released
+    Held(Vec<TunedNote>),
+    /// Bends the held notes, in cents
+    PitchBend(f32),
+}
 
-pub fn run(mut rx: mpsc::Receiver<Either<Chord, Pitches>>) {
-    // .play(
-    let mut handle = None;
+pub fn run(mut rx: mpsc::Receiver<Event>) {
+    let mut voices = None;
+    let bend = Bend::default();
     while let Some(msg) = rx.blocking_recv() {
         match msg {
-            Either::Left(chord) => {
-                handle = Some(chord.play(
-                    Duration::ZERO,
-                    Duration::from_secs(5),
-                    Duration::from_millis(500),
-                ));
-            }
-            Either::Right(pitches) => {
-                handle = Some(pitches.play(
-                    Duration::ZERO,
-                    Duration::from_secs(5),
-                    Duration::from_millis(500),
-                ));
+            Event::Held(notes) => {
+                if voices.is_none() {
+                    voices = Voices::try_new(bend.clone())
+                        .inspect_err(|e| warn!("Failed to open audio output: {e:?}"))
+                        .ok();
+                }
+                if let Some(voices) = voices.as_mut() {
+                    if let Err(e) = voices.set(&notes) {
+                        warn!("Failed to play held notes: {e:?}");
+                    }
+                }
             }
+            Event::PitchBend(cents) => bend.set(cents),
         }
     }
-    drop(handle);
 }
@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+
+use klib::core::note::Note;
+use morivar::TunedNote;
+use rodio::{OutputStream, OutputStreamHandle, Sink, Source};
+
+use crate::bend::{Bend, BentSineWave};
+
+/// Notes which keep ringing until they are released.
+pub struct Voices {
+    _stream: OutputStream,
+    handle: OutputStreamHandle,
+    sinks: HashMap<Note, (f32, Sink)>,
+    bend: Bend,
+}
+
+impl Voices {
+    pub fn try_new(bend: Bend) -> anyhow::Result<Self> {
+        let (stream, handle) = OutputStream::try_default()?;
+        Ok(Self {
+            _stream: stream,
+            handle,
+            sinks: HashMap::new(),
+            bend,
+        })
+    }
+
+    /// Releases the voices not in `notes` and starts the ones not sounding yet.
+    /// Voices which are in `notes` with the same tuning keep ringing undisturbed.
+    pub fn set(&mut self, notes: &[TunedNote]) -> anyhow::Result<()> {
+        // Dropping a sink stops its sound.
+        self.sinks.retain(|note, (cents, _)| {
+            notes
+                .iter()
+                .any(|tuned| tuned.note == *note && tuned.cents == *cents)
+        });
+        for tuned in notes {
+            if self.sinks.contains_key(&tuned.note) {
+                continue;
+            }
+            let sink = Sink::try_new(&self.handle)?;
+            let source = BentSineWave::new(tuned.frequency(), self.bend.clone())
+                .fade_in(std::time::Duration::from_millis(10))
+                .amplify(0.20);
+            sink.append(source);
+            self.sinks.insert(tuned.note, (tuned.cents, sink));
+        }
+        Ok(())
+    }
+}
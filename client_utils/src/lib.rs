@@ -1,11 +1,11 @@
 #![doc = include_str!("../README.md")]
 
-use std::time::Duration;
+use std::{path::Path, time::Duration};
 
 use anyhow::{anyhow, Context};
 use futures_util::SinkExt;
 use http::{uri::Authority, Uri};
-use morivar::{ClientToServer, ToMessage};
+use morivar::{ClientToServer, Heartbeat, ServerToClient, ToMessage};
 use rand::{thread_rng, Rng};
 use simple_tokio_watchdog::{Expired, Signal, Watchdog};
 use tokio::{
@@ -19,6 +19,20 @@ use tokio_native_tls::native_tls;
 use tokio_websockets::{ClientBuilder, MaybeTlsStream, WebsocketStream};
 use tracing::info;
 
+/// The server asked to reconnect to another server, e.g. because it drains for an upgrade.
+#[derive(Debug, thiserror::Error)]
+#[error("Redirected to {0}")]
+pub struct Redirected(pub Authority);
+
+impl Redirected {
+    pub fn parse(address: &str) -> anyhow::Result<Self> {
+        let address = address
+            .parse()
+            .with_context(|| format!("Invalid redirect address \"{address}\""))?;
+        Ok(Self(address))
+    }
+}
+
 pub fn create_uri(uri: Authority, secure: bool) -> Result<Uri, http::Error> {
     Uri::builder()
         .scheme(if secure { "wss" } else { "ws" })
@@ -30,10 +44,22 @@ pub fn create_uri(uri: Authority, secure: bool) -> Result<Uri, http::Error> {
 pub async fn create_client(
     uri: &Uri,
     secure: bool,
+) -> anyhow::Result<WebsocketStream<MaybeTlsStream<TcpStream>>> {
+    create_client_with_identity(uri, secure, None).await
+}
+
+/// Connect, presenting a client certificate if the connection is secure.
+pub async fn create_client_with_identity(
+    uri: &Uri,
+    secure: bool,
+    identity: Option<&native_tls::Identity>,
 ) -> anyhow::Result<WebsocketStream<MaybeTlsStream<TcpStream>>> {
     if secure {
-        let connector = native_tls::TlsConnector::builder().build()?;
-        let connector = tokio_websockets::Connector::NativeTls(connector.into());
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(identity) = identity {
+            builder.identity(identity.clone());
+        }
+        let connector = tokio_websockets::Connector::NativeTls(builder.build()?.into());
 
         ClientBuilder::from_uri(uri.clone())
             .connector(&connector)
@@ -45,7 +71,36 @@ pub async fn create_client(
     .context("Failed to connect to server")
 }
 
-pub async fn announce_protocol_version<S>(stream: &mut WebsocketStream<S>) -> anyhow::Result<()>
+/// Load a client certificate and its PKCS#8 key from PEM files, if both are given.
+///
+/// RSA and EC keys are refused with a hint how to convert them, native-tls only reads PKCS#8.
+pub fn load_identity(
+    cert: Option<&Path>,
+    key: Option<&Path>,
+) -> anyhow::Result<Option<native_tls::Identity>> {
+    let (Some(cert), Some(key_path)) = (cert, key) else {
+        return Ok(None);
+    };
+    let cert = std::fs::read(cert).with_context(|| format!("Failed to read {cert:?}"))?;
+    let key = std::fs::read(key_path).with_context(|| format!("Failed to read {key_path:?}"))?;
+    for format in ["RSA", "EC"] {
+        let header = format!("-----BEGIN {format} PRIVATE KEY-----");
+        anyhow::ensure!(
+            !String::from_utf8_lossy(&key).contains(&header),
+            "{key_path:?} is an {format} key, clients need PKCS#8: \
+             convert it with `openssl pkcs8 -topk8 -nocrypt -in {}`",
+            key_path.display()
+        );
+    }
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key)
+        .context("Invalid client certificate or PKCS#8 key")?;
+    Ok(Some(identity))
+}
+
+/// Announces the protocol version, then waits for the heartbeat policy the server announces in return.
+pub async fn announce_protocol_version<S>(
+    stream: &mut WebsocketStream<S>,
+) -> anyhow::Result<Heartbeat>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
@@ -54,18 +109,67 @@ where
     stream
         .send(version.to_message())
         .await
-        .context("Failed to send protocol version")
+        .context("Failed to send protocol version")?;
+
+    let Some(Ok(text)) = receive_text(stream).await else {
+        anyhow::bail!("Server closed the connection instead of announcing its heartbeat");
+    };
+    let Ok(ServerToClient::Heartbeat(heartbeat)) = serde_json::from_str(&text) else {
+        anyhow::bail!("Expected heartbeat announcement, got: {text}");
+    };
+    info!("Server announced heartbeat {heartbeat:?}");
+    Ok(heartbeat)
+}
+
+/// Receive the next text message, skipping the ping and pong frames quinnipak probes the connection with.
+///
+/// `None` once the server closed the connection.
+pub async fn receive_text<S>(stream: &mut WebsocketStream<S>) -> Option<anyhow::Result<String>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let message = match stream.next().await? {
+            Ok(message) => message,
+            Err(e) => return Some(Err(e).context("Failed to receive message")),
+        };
+        if message.is_ping() || message.is_pong() {
+            // Pings are answered by the websocket stream itself.
+            continue;
+        }
+        if message.is_close() {
+            return None;
+        }
+        let Ok(text) = message.as_text() else {
+            return Some(Err(anyhow!("Expected text message, got: {message:?}")));
+        };
+        return Some(Ok(text.to_string()));
+    }
+}
+
+/// How a client identifies to quinnipak.
+#[derive(Debug, Clone)]
+pub struct Identification {
+    pub id: String,
+    pub room: Option<String>,
+    /// Authenticates the client, if quinnipak requires it
+    pub token: Option<String>,
 }
 
 pub async fn announce_as_consumer<S>(
-    id: impl ToString,
+    identification: &Identification,
     stream: &mut WebsocketStream<S>,
 ) -> anyhow::Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     info!("Announcing as consumer");
-    let announce = ClientToServer::IAmConsumer { id: id.to_string() };
+    let announce = ClientToServer::IAmConsumer {
+        id: identification.id.clone(),
+        room: identification.room.clone(),
+        token: identification.token.clone(),
+        relayed_to: Vec::new(),
+    };
     stream
         .send(announce.to_message())
         .await
@@ -73,14 +177,18 @@ where
 }
 
 pub async fn announce_as_publisher<S>(
-    id: impl ToString,
+    identification: &Identification,
     stream: &mut WebsocketStream<S>,
 ) -> anyhow::Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     info!("Announcing as publisher");
-    let announce = ClientToServer::IAmPublisher { id: id.to_string() };
+    let announce = ClientToServer::IAmPublisher {
+        id: identification.id.clone(),
+        room: identification.room.clone(),
+        token: identification.token.clone(),
+    };
     stream
         .send(announce.to_message())
         .await
@@ -88,10 +196,15 @@ where
 }
 
 pub async fn create_watchdog(
+    heartbeat: &Heartbeat,
 ) -> anyhow::Result<(Interval, mpsc::Sender<Signal>, oneshot::Receiver<Expired>)> {
-    let interval = tokio::time::interval(morivar::PING_INTERVAL);
+    anyhow::ensure!(
+        !heartbeat.ping_interval.is_zero() && !heartbeat.ping_to_pong_allowed_delay.is_zero(),
+        "Server announced a heartbeat with zero durations: {heartbeat:?}"
+    );
+    let interval = tokio::time::interval(heartbeat.ping_interval);
 
-    let (watchdog, expiration) = Watchdog::with_timeout(morivar::PING_TO_PONG_ALLOWED_DELAY).run();
+    let (watchdog, expiration) = Watchdog::with_timeout(heartbeat.ping_to_pong_allowed_delay).run();
     watchdog
         .send(Signal::Stop)
         .await
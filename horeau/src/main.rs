@@ -51,6 +51,11 @@ impl Model {
             html!(
                 <p>{ format!("{}", chord.name()) }</p>
             )
+        } else if let Some(ServerToConsumer::RecognizedPitchesEvent { chords, .. }) = &self.data {
+            let names = chords.iter().map(|chord| chord.name()).collect::<Vec<_>>();
+            html!(
+                <p>{ names.join(" / ") }</p>
+            )
         } else if let Ok(value) = serde_json::to_string_pretty(&self.data) {
             html! {
                 <p>{ value }</p>
@@ -97,7 +102,12 @@ impl Component for Model {
                     true
                 }
                 WsAction::Identify(id) => {
-                    let message = ClientToServer::IAmConsumer { id };
+                    let message = ClientToServer::IAmConsumer {
+                        id,
+                        room: None,
+                        token: None,
+                        relayed_to: Vec::new(),
+                    };
                     self.ws
                         .as_mut()
                         .unwrap()
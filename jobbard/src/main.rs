@@ -6,8 +6,8 @@ use std::{fs::File, io::BufReader, path::PathBuf, sync::Arc, time::Duration};
 use anyhow::Context;
 use clap::{command, Parser};
 use client_utils::{
-    announce_as_publisher, announce_protocol_version, create_client, create_uri, create_watchdog,
-    flatten,
+    announce_as_publisher, announce_protocol_version, create_client_with_identity, create_uri,
+    create_watchdog, flatten, load_identity, receive_text, Identification, Redirected,
 };
 use futures_util::SinkExt;
 use klib::core::{
@@ -41,6 +41,10 @@ struct Arguments {
     /// The interval to play new chords at
     #[arg(long, default_value_t = Duration::from_secs(5).into())]
     interval: humantime::Duration,
+
+    /// How long each chord rings before the server releases it, until the next chord if not given
+    #[arg(long)]
+    duration: Option<humantime::Duration>,
 }
 
 fn simple_sequence() -> [Chord; 4] {
@@ -65,9 +69,15 @@ async fn main() -> anyhow::Result<()> {
     let song = args.song;
     let template = args.template;
     let interval = args.interval;
+    let duration = args.duration.map(Duration::from);
     let args = args.args;
     let secure = args.secure;
-    let id = args.id;
+    let identity = load_identity(args.client_cert.as_deref(), args.client_key.as_deref())?;
+    let identification = Identification {
+        id: args.id,
+        room: args.room,
+        token: args.token,
+    };
 
     if let Some(path) = template {
         let song = simple_sequence();
@@ -79,18 +89,28 @@ async fn main() -> anyhow::Result<()> {
     let song: Vec<Chord> = serde_json::from_reader(BufReader::new(File::open(song)?))?;
     let song = Arc::new(song);
 
-    let uri = create_uri(args.url, secure)?;
+    let mut uri = create_uri(args.url, secure)?;
 
     loop {
-        let id = id.clone();
-        let uri = uri.clone();
+        let identification = identification.clone();
+        let connect_uri = uri.clone();
         let song = Arc::clone(&song);
+        let identity = identity.clone();
         let handle = tokio::spawn(async move {
             let song = song.iter().cycle();
             info!("Attempting to connect to server");
-            let mut stream = create_client(&uri, secure).await?;
-
-            let result = jobbard(&mut stream, &id, args.pingpong, &interval, song.clone()).await;
+            let mut stream =
+                create_client_with_identity(&connect_uri, secure, identity.as_ref()).await?;
+
+            let result = jobbard(
+                &mut stream,
+                &identification,
+                args.pingpong,
+                &interval,
+                duration,
+                song.clone(),
+            )
+            .await;
             if let Err(e) = stream
                 .send(PublisherToServer::PublishSilence.to_message())
                 .await
@@ -102,8 +122,14 @@ async fn main() -> anyhow::Result<()> {
             }
             result
         });
-        let error = tokio::try_join!(flatten(handle));
-        warn!("Failed to handle connection: {error:?}");
+        if let Err(e) = flatten(handle).await {
+            if let Some(Redirected(address)) = e.downcast_ref() {
+                info!("Following redirect to {address}");
+                uri = create_uri(address.clone(), secure)?;
+                continue;
+            }
+            warn!("Failed to handle connection: {e:?}");
+        }
 
         tokio::time::sleep(client_utils::jittering_retry_duration()).await;
     }
@@ -112,43 +138,51 @@ async fn main() -> anyhow::Result<()> {
 /// Handle the client connection
 async fn jobbard<S>(
     stream: &mut WebsocketStream<S>,
-    id: &str,
+    identification: &Identification,
     pingpong: bool,
     interval: &Duration,
+    duration: Option<Duration>,
     mut song: impl Iterator<Item = &Chord>,
 ) -> anyhow::Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    announce_protocol_version(stream).await?;
+    let heartbeat = announce_protocol_version(stream).await?;
 
-    announce_as_publisher(id, stream).await?;
+    announce_as_publisher(identification, stream).await?;
 
     let mut chord_interval = tokio::time::interval(*interval + Duration::from_millis(500));
 
-    let (mut interval, watchdog, mut expiration) = create_watchdog().await?;
+    let (mut interval, watchdog, mut expiration) = create_watchdog(&heartbeat).await?;
 
     loop {
         select! {
-            msg = stream.next() => {
+            msg = receive_text(stream) => {
                 let Some(Ok(msg)) = msg else {
                     anyhow::bail!("Error receiving message: {msg:?}");
                 };
-                let Ok(msg) = msg.as_text() else {
-                   anyhow::bail!("Expected text message, got: {msg:?}");
-                };
-                let Ok(ServerToPublisher::Pong) = serde_json::from_str(msg) else {
-                    anyhow::bail!("Expected Pong, got: {msg:?}");
-                };
-                watchdog.send(Signal::Stop).await.context("Failed to stop watchdog")?;
+                match serde_json::from_str(&msg) {
+                    Ok(ServerToPublisher::Pong) => {
+                        watchdog.send(Signal::Stop).await.context("Failed to stop watchdog")?;
+                    }
+                    Ok(ServerToPublisher::Redirect { address }) => {
+                        return Err(Redirected::parse(&address)?.into());
+                    }
+                    Ok(ServerToPublisher::FloorGranted) => info!("Got the floor, publishing"),
+                    Ok(ServerToPublisher::FloorLost) => {
+                        info!("Lost the floor, events are dropped until it is free");
+                    }
+                    Ok(ServerToPublisher::RateLimited) => {
+                        warn!("Publishing too fast, the server drops events over its rate limit");
+                    }
+                    _ => anyhow::bail!("Expected Pong, got: {msg:?}"),
+                }
             }
             _p = chord_interval.tick() => {
                 let chord = song.next().unwrap();
                 info!("Sending chord {chord}");
-                stream
-                    .send(PublisherToServer::PublishChord(chord.clone()).to_message())
-                    .await?;
-                stream.send(PublisherToServer::PublishSilence.to_message()).await?;
+                let chord = PublisherToServer::PublishChord { chord: chord.clone(), duration };
+                stream.send(chord.to_message()).await?;
             }
             _i = interval.tick(), if pingpong => {
                 info!("Sending Ping!");
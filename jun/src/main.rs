@@ -0,0 +1,187 @@
+#![doc = include_str!("../README.md")]
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use client_utils::{
+    announce_as_consumer, announce_protocol_version, create_client_with_identity, create_uri,
+    create_watchdog, load_identity, receive_text, Identification,
+};
+use futures_util::SinkExt;
+use morivar::{ConsumerToServer, ServerToConsumer, ToMessage};
+use score::Score;
+use serde::Deserialize;
+use simple_tokio_watchdog::{Expired, Signal};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    select,
+    time::Instant,
+};
+use tokio_websockets::WebsocketStream;
+use tracing::{info, warn};
+
+mod score;
+
+#[derive(Debug, Parser)]
+#[command(author, version)]
+struct Arguments {
+    /// The Standard MIDI File to write
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// The velocity notes start with unless their delta carries one
+    #[arg(long, default_value_t = 96, value_parser = clap::value_parser!(u8).range(1..=127))]
+    velocity: u8,
+
+    /// The range of the pitch bend wheel in either direction, in semitones
+    #[arg(long, default_value_t = 2.0)]
+    pitch_bend_range: f32,
+
+    #[command(subcommand)]
+    source: Source,
+}
+
+#[derive(Debug, Subcommand)]
+enum Source {
+    /// Consume a room, writing the file when the connection ends or on Ctrl-C
+    Live {
+        #[command(flatten)]
+        args: morivar::cli::ClientArguments,
+    },
+    /// Convert a session recorded with `quinnipak --record`
+    Log { file: PathBuf },
+}
+
+/// The part of a recorded event the score needs.
+#[derive(Debug, Deserialize)]
+struct Entry {
+    /// Milliseconds since the Unix epoch
+    time: u64,
+    publisher: String,
+    event: ServerToConsumer,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let args = Arguments::parse();
+    let mut score = Score::new(args.velocity, args.pitch_bend_range);
+
+    let end = match args.source {
+        Source::Live { args } => {
+            let identification = Identification {
+                id: args.id,
+                room: args.room,
+                token: args.token,
+            };
+            let identity = load_identity(args.client_cert.as_deref(), args.client_key.as_deref())?;
+            let uri = create_uri(args.url, args.secure)?;
+            info!("Attempting to connect to server");
+            let mut stream =
+                create_client_with_identity(&uri, args.secure, identity.as_ref()).await?;
+            jun(&mut stream, &identification, args.pingpong, &mut score).await?
+        }
+        Source::Log { file } => read_log(&file, &mut score)?,
+    };
+
+    let smf = score.finish(end);
+    smf.save(&args.output)
+        .with_context(|| format!("Failed to write {:?}", args.output))?;
+    info!("Wrote {} tracks to {:?}", smf.tracks.len() - 1, args.output);
+    Ok(())
+}
+
+/// Score a recorded session, returning when its last event happened.
+fn read_log(path: &Path, score: &mut Score) -> anyhow::Result<Duration> {
+    let file = File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+    let mut start = None;
+    let mut end = Duration::ZERO;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.with_context(|| format!("Failed to read {path:?}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Entry = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid event on line {} of {path:?}", index + 1))?;
+        let start = *start.get_or_insert(entry.time);
+        end = Duration::from_millis(entry.time.saturating_sub(start));
+        score.play(&entry.publisher, end, &entry.event);
+    }
+    Ok(end)
+}
+
+/// Score what the room plays until the connection ends, returning when it ended.
+///
+/// Consumers don't learn who published an event, so everything becomes one part named after the room.
+async fn jun<S>(
+    stream: &mut WebsocketStream<S>,
+    identification: &Identification,
+    pingpong: bool,
+    score: &mut Score,
+) -> anyhow::Result<Duration>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let heartbeat = announce_protocol_version(stream).await?;
+
+    announce_as_consumer(identification, stream).await?;
+
+    let (mut interval, watchdog, mut expiration) = create_watchdog(&heartbeat).await?;
+    let part = identification
+        .room
+        .as_deref()
+        .unwrap_or(morivar::DEFAULT_ROOM);
+    let start = Instant::now();
+    info!("Scoring room \"{part}\", stop with Ctrl-C");
+
+    loop {
+        select! {
+            msg = receive_text(stream) => {
+                let Some(Ok(text)) = msg else {
+                    warn!("Breaking on client message: {msg:?}");
+                    break;
+                };
+                if pingpong {
+                    // on any message, even non-pong, stop the watchdog - the server is alive at least.
+                    watchdog.send(Signal::Stop).await.context("Failed to reset the watchdog")?;
+                }
+                match serde_json::from_str(&text) {
+                    Ok(ServerToConsumer::Redirect { address }) => {
+                        warn!("Server is going away to {address}, stopping");
+                        break;
+                    }
+                    Ok(ServerToConsumer::Missed { count }) => {
+                        warn!("Fell behind, the server skipped {count} events");
+                    }
+                    Ok(event) => score.play(part, start.elapsed(), &event),
+                    Err(e) => warn!("Protocol error, expected ServerToConsumer: {e:?}"),
+                }
+            }
+            _i = interval.tick(), if pingpong => {
+                watchdog.send(Signal::Reset).await?;
+                stream.send(ConsumerToServer::Ping.to_message()).await?;
+            }
+            e = &mut expiration, if pingpong => {
+                let Expired = e.context("Failed to monitor watchdog")?;
+                warn!("Server failed to pong, stopping");
+                break;
+            }
+            interrupt = tokio::signal::ctrl_c() => {
+                interrupt.context("Failed to listen for Ctrl-C")?;
+                info!("Stopping");
+                break;
+            }
+        }
+    }
+
+    let end = start.elapsed();
+    let _ = stream.close(None, None).await;
+    Ok(end)
+}
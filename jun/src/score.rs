@@ -0,0 +1,273 @@
+use std::{collections::BTreeSet, time::Duration};
+
+use klib::core::{
+    chord::HasChord,
+    note::{HasNoteId, Note},
+};
+use midly::{
+    num::{u15, u24, u28, u4, u7},
+    Format, Header, MetaMessage, MidiMessage, PitchBend, Smf, Timing, TrackEvent, TrackEventKind,
+};
+use morivar::{NotesDelta, ServerToConsumer, SoundingNotes};
+
+/// Ticks per quarter note, at the tempo below one tick is one millisecond.
+const TICKS_PER_QUARTER: u16 = 500;
+/// Microseconds per quarter note, 120 bpm.
+const TEMPO: u32 = 500_000;
+/// The velocity notes stop with.
+const RELEASE_VELOCITY: u8 = 64;
+
+/// What several publishers played, one part each, becoming one track each.
+#[derive(Debug)]
+pub struct Score {
+    /// The velocity notes start with unless their delta carries one
+    velocity: u7,
+    /// The pitch bend range in semitones in either direction
+    pitch_bend_range: f32,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug)]
+struct Part {
+    name: String,
+    channel: u4,
+    sounding: BTreeSet<u8>,
+    notes: SoundingNotes,
+    /// MIDI messages by milliseconds since the start
+    events: Vec<(u64, MidiMessage)>,
+}
+
+impl Score {
+    pub fn new(velocity: u8, pitch_bend_range: f32) -> Self {
+        Self {
+            velocity: clamp_velocity(velocity),
+            pitch_bend_range,
+            parts: Vec::new(),
+        }
+    }
+
+    /// Add what `publisher` played at `at` since the start.
+    pub fn play(&mut self, publisher: &str, at: Duration, event: &ServerToConsumer) {
+        let velocity = match event {
+            ServerToConsumer::NotesOn(NotesDelta {
+                velocity: Some(velocity),
+                ..
+            }) => clamp_velocity(*velocity),
+            _ => self.velocity,
+        };
+        let pitch_bend_range = self.pitch_bend_range;
+        let part = self.part(publisher);
+        let at = at.as_millis() as u64;
+        let notes: Vec<Note> = match event {
+            ServerToConsumer::ChordEvent(chord) => chord.chord(),
+            ServerToConsumer::PitchesEvent(pitches)
+            | ServerToConsumer::RecognizedPitchesEvent { pitches, .. } => {
+                pitches.iter().copied().collect()
+            }
+            ServerToConsumer::TunedPitchesEvent(pitches) => {
+                pitches.iter().map(|tuned| tuned.note).collect()
+            }
+            ServerToConsumer::NotesOn(delta) => {
+                part.notes.notes_on(delta);
+                part.notes.notes().iter().copied().collect()
+            }
+            ServerToConsumer::NotesOff(delta) => {
+                part.notes.notes_off(delta);
+                part.notes.notes().iter().copied().collect()
+            }
+            ServerToConsumer::Keyframe(keyframe) => {
+                part.notes.keyframe(keyframe);
+                part.notes.notes().iter().copied().collect()
+            }
+            ServerToConsumer::PitchBendEvent { cents } => {
+                let bend = PitchBend::from_f32(cents / (pitch_bend_range * 100.0));
+                part.events.push((at, MidiMessage::PitchBend { bend }));
+                return;
+            }
+            ServerToConsumer::Silence => Vec::new(),
+            ServerToConsumer::Pong
+            | ServerToConsumer::Redirect { .. }
+            | ServerToConsumer::Missed { .. } => return,
+        };
+        if !matches!(
+            event,
+            ServerToConsumer::NotesOn(_)
+                | ServerToConsumer::NotesOff(_)
+                | ServerToConsumer::Keyframe(_)
+        ) {
+            part.notes.clear();
+        }
+        part.sound(at, notes.into_iter().filter_map(key).collect(), velocity);
+    }
+
+    /// Stop all notes at `at` and render a type-1 Standard MIDI File, with a tempo track first.
+    pub fn finish(&mut self, at: Duration) -> Smf<'_> {
+        let at = at.as_millis() as u64;
+        let velocity = self.velocity;
+        for part in &mut self.parts {
+            part.sound(at, BTreeSet::new(), velocity);
+        }
+        let mut smf = Smf::new(Header::new(
+            Format::Parallel,
+            Timing::Metrical(u15::new(TICKS_PER_QUARTER)),
+        ));
+        smf.tracks.push(vec![
+            meta(0, MetaMessage::Tempo(u24::new(TEMPO))),
+            meta(0, MetaMessage::EndOfTrack),
+        ]);
+        for part in &self.parts {
+            let mut track = vec![meta(0, MetaMessage::TrackName(part.name.as_bytes()))];
+            let mut previous = 0;
+            for (time, message) in &part.events {
+                track.push(TrackEvent {
+                    delta: u28::new(time.saturating_sub(previous) as u32),
+                    kind: TrackEventKind::Midi {
+                        channel: part.channel,
+                        message: *message,
+                    },
+                });
+                previous = *time;
+            }
+            track.push(meta(0, MetaMessage::EndOfTrack));
+            smf.tracks.push(track);
+        }
+        smf
+    }
+
+    fn part(&mut self, publisher: &str) -> &mut Part {
+        let index = match self.parts.iter().position(|part| part.name == publisher) {
+            Some(index) => index,
+            None => {
+                // Each part bends on its own channel, skipping the percussion channel.
+                let channel =
+                    [0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 12, 13, 14, 15][self.parts.len() % 15];
+                self.parts.push(Part {
+                    name: publisher.to_string(),
+                    channel: u4::new(channel),
+                    sounding: BTreeSet::new(),
+                    notes: SoundingNotes::default(),
+                    events: Vec::new(),
+                });
+                self.parts.len() - 1
+            }
+        };
+        &mut self.parts[index]
+    }
+}
+
+impl Part {
+    /// Stop the sounding keys not in `keys` and start the others.
+    fn sound(&mut self, at: u64, keys: BTreeSet<u8>, velocity: u7) {
+        for &key in self.sounding.difference(&keys) {
+            let message = MidiMessage::NoteOff {
+                key: u7::new(key),
+                vel: u7::new(RELEASE_VELOCITY),
+            };
+            self.events.push((at, message));
+        }
+        for &key in keys.difference(&self.sounding) {
+            let message = MidiMessage::NoteOn {
+                key: u7::new(key),
+                vel: velocity,
+            };
+            self.events.push((at, message));
+        }
+        self.sounding = keys;
+    }
+}
+
+fn clamp_velocity(velocity: u8) -> u7 {
+    u7::new(velocity.clamp(1, 127))
+}
+
+/// The MIDI key of `note`, if it has one.
+fn key(note: Note) -> Option<u8> {
+    let key = note.id().trailing_zeros();
+    u8::try_from(key).ok().filter(|key| *key < 128)
+}
+
+fn meta(delta: u32, message: MetaMessage<'_>) -> TrackEvent<'_> {
+    TrackEvent {
+        delta: u28::new(delta),
+        kind: TrackEventKind::Meta(message),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use klib::core::{named_pitch::NamedPitch, octave::Octave};
+
+    use super::*;
+
+    #[test]
+    fn renders_a_track_per_publisher() {
+        let pitches = |pitches: &[NamedPitch]| {
+            ServerToConsumer::PitchesEvent(
+                pitches
+                    .iter()
+                    .map(|pitch| Note::new(*pitch, Octave::Four))
+                    .collect::<HashSet<_>>(),
+            )
+        };
+        let mut score = Score::new(100, 2.0);
+        let second = Duration::from_secs(1);
+        score.play(
+            "alto",
+            Duration::ZERO,
+            &pitches(&[NamedPitch::C, NamedPitch::E]),
+        );
+        score.play("tenor", second, &pitches(&[NamedPitch::G]));
+        score.play("alto", second * 2, &pitches(&[NamedPitch::E]));
+        score.play("alto", second * 3, &ServerToConsumer::Silence);
+        let smf = score.finish(second * 4);
+
+        assert_eq!(smf.header.format, Format::Parallel);
+        assert_eq!(smf.tracks.len(), 3);
+        let alto: Vec<_> = smf.tracks[1]
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { message, .. } => Some((event.delta.as_int(), message)),
+                _ => None,
+            })
+            .collect();
+        // Two notes on, C off after two seconds, E off after three.
+        assert_eq!(alto.len(), 4);
+        assert!(matches!(alto[1], (0, MidiMessage::NoteOn { vel, .. }) if vel.as_int() == 100));
+        assert!(matches!(alto[2], (2000, MidiMessage::NoteOff { .. })));
+        assert!(matches!(alto[3], (1000, MidiMessage::NoteOff { .. })));
+        let tenor_channels: HashSet<_> = smf.tracks[2]
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { channel, .. } => Some(channel.as_int()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(tenor_channels, HashSet::from([1]));
+    }
+
+    #[test]
+    fn keeps_delta_velocities() {
+        let a4 = Note::new(NamedPitch::A, Octave::Four);
+        let mut score = Score::new(100, 2.0);
+        score.play(
+            "alto",
+            Duration::ZERO,
+            &ServerToConsumer::NotesOn(NotesDelta {
+                sequence: 1,
+                previous: 0,
+                notes: HashSet::from([a4]),
+                velocity: Some(30),
+            }),
+        );
+        let smf = score.finish(Duration::from_secs(1));
+        assert!(smf.tracks[1].iter().any(|event| matches!(
+            event.kind,
+            TrackEventKind::Midi {
+                message: MidiMessage::NoteOn { vel, .. },
+                ..
+            } if vel.as_int() == 30
+        )));
+    }
+}
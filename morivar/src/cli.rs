@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use clap::{Args, ValueHint};
 use http::uri::Authority;
 
@@ -11,10 +13,28 @@ pub struct ClientArguments {
     #[arg(short, long)]
     pub id: String,
 
+    /// The room to join on Quinnipak
+    #[arg(short, long)]
+    pub room: Option<String>,
+
+    /// A token to authenticate with, if the server requires one
+    #[arg(long)]
+    pub token: Option<String>,
+
     /// Whether to secure the connection (requires certificates for the server)
     #[arg(short, long, default_value_t = false)]
     pub secure: bool,
 
+    /// A client certificate to present on secure connections, which publishers may need
+    #[arg(long, requires = "client_key", value_hint = ValueHint::FilePath)]
+    pub client_cert: Option<PathBuf>,
+
+    /// The key of the client certificate, as PKCS#8 PEM (`BEGIN PRIVATE KEY`)
+    ///
+    /// Unlike quinnipak, clients can't read RSA or EC keys, convert them with `openssl pkcs8 -topk8 -nocrypt`.
+    #[arg(long, requires = "client_cert", value_hint = ValueHint::FilePath)]
+    pub client_key: Option<PathBuf>,
+
     /// Whether to periodically ping the server
     #[arg(short, long, default_value_t = false)]
     pub pingpong: bool,
@@ -7,6 +7,10 @@ use std::{collections::HashSet, time::Duration};
 use klib::core::{chord::Chord, note::Note};
 use serde::{Deserialize, Serialize};
 
+pub mod notes;
+
+pub use notes::{NotesDelta, NotesKeyframe, SoundingNotes, TunedNote};
+
 #[cfg(feature = "message")]
 pub mod to_message;
 
@@ -20,26 +24,93 @@ pub const PING_TO_PONG_ALLOWED_DELAY: Duration = Duration::from_secs(5);
 #[cfg(feature = "cli")]
 pub mod cli;
 
-pub const PROTOCOL_VERSION: u32 = 1;
+pub const PROTOCOL_VERSION: u32 = 7;
 
 pub const CLIENT_RECONNECT_DURATION: Duration = Duration::from_millis(500);
 
+/// The room clients join when they don't ask for a specific one.
+pub const DEFAULT_ROOM: &str = "default";
+
+/// The heartbeat policy a server announces to its clients during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Heartbeat {
+    /// How often clients should ping the server
+    pub ping_interval: Duration,
+    /// How long the server waits for a ping before cutting the connection
+    pub ping_await_interval: Duration,
+    /// How long clients should wait for a pong before giving up on the server
+    pub ping_to_pong_allowed_delay: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            ping_interval: PING_INTERVAL,
+            ping_await_interval: PING_AWAIT_INTERVAL,
+            ping_to_pong_allowed_delay: PING_TO_PONG_ALLOWED_DELAY,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClientToServer {
     IAmPublisher {
         id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+        /// Authenticates the client, if the server requires it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
     },
     IAmConsumer {
         id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+        /// Sent by a relaying server: its own id, then those of the servers it relays the room to
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        relayed_to: Vec<String>,
     },
     #[serde(rename = "PublisherProtocolVersion")]
     ProtocolVersion(u32),
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ServerToClient {
+    /// Sent once the protocol version was accepted, before identification
+    Heartbeat(Heartbeat),
+    /// The client may not identify the way it tried to, for the given reason, and is disconnected
+    Unauthorized(String),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PublisherToServer {
-    PublishChord(Chord),
-    PublishPitches(HashSet<Note>),
+    /// Sounds `chord`, released by the server after `duration` if given
+    PublishChord {
+        chord: Chord,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        duration: Option<Duration>,
+    },
+    /// Sounds `pitches`, released by the server after `duration` if given
+    PublishPitches {
+        pitches: HashSet<Note>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        duration: Option<Duration>,
+    },
+    PublishNotesOn(NotesDelta),
+    PublishNotesOff(NotesDelta),
+    PublishKeyframe(NotesKeyframe),
+    /// Sounds `pitches`, released by the server after `duration` if given
+    PublishTunedPitches {
+        pitches: Vec<TunedNote>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        duration: Option<Duration>,
+    },
+    /// Bends everything sounding by `cents`, until the next pitch bend
+    PublishPitchBend {
+        cents: f32,
+    },
     PublishSilence,
     Ping,
 }
@@ -49,6 +120,16 @@ pub enum ServerToPublisher {
     Pong,
     NowAreYou,
     Error(String),
+    /// The server is going away, reconnect to the server at `address` (`host:port`)
+    Redirect {
+        address: String,
+    },
+    /// This publisher's events now reach the consumers of its room
+    FloorGranted,
+    /// Another publisher took the floor, this publisher's events are dropped until it is free again
+    FloorLost,
+    /// The publisher exceeded its rate limit, events over it are dropped or coalesced
+    RateLimited,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -60,8 +141,28 @@ pub enum ConsumerToServer {
 pub enum ServerToConsumer {
     ChordEvent(Chord),
     PitchesEvent(HashSet<Note>),
+    /// Pitches the server recognized chords in, the best-ranked candidate first
+    RecognizedPitchesEvent {
+        pitches: HashSet<Note>,
+        chords: Vec<Chord>,
+    },
+    NotesOn(NotesDelta),
+    NotesOff(NotesDelta),
+    Keyframe(NotesKeyframe),
+    TunedPitchesEvent(Vec<TunedNote>),
+    PitchBendEvent {
+        cents: f32,
+    },
     Silence,
     Pong,
+    /// The server is going away, reconnect to the server at `address` (`host:port`)
+    Redirect {
+        address: String,
+    },
+    /// The consumer fell behind and skipped `count` events, the events restoring what currently sounds follow
+    Missed {
+        count: u64,
+    },
 }
 
 #[cfg(test)]
@@ -74,6 +175,9 @@ mod test {
     fn serializes_announce() {
         let message = ClientToServer::IAmConsumer {
             id: "Hello there".to_string(),
+            room: None,
+            token: None,
+            relayed_to: Vec::new(),
         };
         dbg!(serde_json::to_string_pretty(&message).unwrap());
     }
@@ -82,6 +186,8 @@ mod test {
     fn serializes_piano_announce() {
         let message = ClientToServer::IAmPublisher {
             id: "Hello there".to_string(),
+            room: Some("rehearsal".to_string()),
+            token: Some("eyJhbGciOiJIUzI1NiJ9.e30.signature".to_string()),
         };
         println!("{}", serde_json::to_string_pretty(&message).unwrap());
     }
@@ -89,7 +195,10 @@ mod test {
     #[test]
     fn serializes_piano_chord() {
         let chord = Chord::new(note::AFlat).sus4().seven().add13();
-        let message = PublisherToServer::PublishChord(chord);
+        let message = PublisherToServer::PublishChord {
+            chord,
+            duration: Some(Duration::from_millis(1500)),
+        };
         println!("{:?}", serde_json::to_string_pretty(&message).unwrap());
     }
 
@@ -101,7 +210,48 @@ mod test {
         ]
         .into_iter()
         .collect();
-        let message = PublisherToServer::PublishPitches(chord);
+        let message = PublisherToServer::PublishPitches {
+            pitches: chord,
+            duration: None,
+        };
         println!("{:?}", serde_json::to_string(&message).unwrap());
     }
+
+    #[test]
+    fn heartbeat_roundtrips() {
+        let message = ServerToClient::Heartbeat(Heartbeat::default());
+        let text = serde_json::to_string(&message).unwrap();
+        assert!(matches!(
+            serde_json::from_str(&text).unwrap(),
+            ServerToClient::Heartbeat(heartbeat) if heartbeat == Heartbeat::default()
+        ));
+    }
+
+    #[test]
+    fn omits_missing_duration() {
+        let message = PublisherToServer::PublishTunedPitches {
+            pitches: vec![TunedNote::from(Note::new(NamedPitch::A, Octave::Four))],
+            duration: None,
+        };
+        let text = serde_json::to_string(&message).unwrap();
+        assert!(!text.contains("duration"));
+        assert!(matches!(
+            serde_json::from_str(&text).unwrap(),
+            PublisherToServer::PublishTunedPitches { duration: None, .. }
+        ));
+    }
+
+    #[test]
+    fn deserializes_announce_without_room() {
+        let message: ClientToServer =
+            serde_json::from_str(r#"{"IAmConsumer":{"id":"Hello there"}}"#).unwrap();
+        assert!(matches!(
+            message,
+            ClientToServer::IAmConsumer {
+                room: None,
+                token: None,
+                ..
+            }
+        ));
+    }
 }
@@ -0,0 +1,175 @@
+use std::collections::HashSet;
+
+use klib::core::{note::Note, pitch::HasFrequency};
+use serde::{Deserialize, Serialize};
+
+/// A note detuned from its equal-tempered pitch, e.g. for just intonation or quarter tones.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TunedNote {
+    pub note: Note,
+    /// The offset from the equal-tempered pitch, in cents (hundredths of a semitone)
+    #[serde(default)]
+    pub cents: f32,
+}
+
+impl TunedNote {
+    pub fn frequency(&self) -> f32 {
+        detune(self.note.frequency(), self.cents)
+    }
+}
+
+impl From<Note> for TunedNote {
+    fn from(note: Note) -> Self {
+        Self { note, cents: 0.0 }
+    }
+}
+
+/// Shifts `frequency` by `cents`.
+pub fn detune(frequency: f32, cents: f32) -> f32 {
+    frequency * 2f32.powf(cents / 1200.0)
+}
+
+/// Notes which started or stopped sounding since the event with sequence number `previous`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotesDelta {
+    pub sequence: u64,
+    pub previous: u64,
+    pub notes: HashSet<Note>,
+    /// How hard started notes were struck, from 1 to 127, if the publisher knows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub velocity: Option<u8>,
+}
+
+/// The complete set of sounding notes, sent periodically so consumers can resynchronize.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotesKeyframe {
+    pub sequence: u64,
+    pub notes: HashSet<Note>,
+}
+
+/// Tracks the sounding notes from deltas and keyframes.
+#[derive(Debug, Clone, Default)]
+pub struct SoundingNotes {
+    sequence: Option<u64>,
+    notes: HashSet<Note>,
+}
+
+impl SoundingNotes {
+    pub fn notes(&self) -> &HashSet<Note> {
+        &self.notes
+    }
+
+    /// Applies a delta of started notes.
+    ///
+    /// Returns `false` if the delta does not follow on the last applied event.
+    /// The notes may then be wrong until the next keyframe.
+    pub fn notes_on(&mut self, delta: &NotesDelta) -> bool {
+        self.notes.extend(delta.notes.iter().copied());
+        self.advance(delta)
+    }
+
+    /// Applies a delta of stopped notes.
+    ///
+    /// Returns `false` if the delta does not follow on the last applied event.
+    /// The notes may then be wrong until the next keyframe.
+    pub fn notes_off(&mut self, delta: &NotesDelta) -> bool {
+        self.notes.retain(|note| !delta.notes.contains(note));
+        self.advance(delta)
+    }
+
+    pub fn keyframe(&mut self, keyframe: &NotesKeyframe) {
+        self.sequence = Some(keyframe.sequence);
+        self.notes.clone_from(&keyframe.notes);
+    }
+
+    /// Forgets all notes, e.g. on silence.
+    pub fn clear(&mut self) {
+        self.sequence = None;
+        self.notes.clear();
+    }
+
+    fn advance(&mut self, delta: &NotesDelta) -> bool {
+        let in_sync = self.sequence == Some(delta.previous);
+        self.sequence = in_sync.then_some(delta.sequence);
+        in_sync
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use klib::core::{named_pitch::NamedPitch, octave::Octave};
+
+    use super::*;
+
+    fn a4() -> Note {
+        Note::new(NamedPitch::A, Octave::Four)
+    }
+
+    fn c5() -> Note {
+        Note::new(NamedPitch::C, Octave::Five)
+    }
+
+    #[test]
+    fn detunes_by_cents() {
+        let concert_a = TunedNote::from(a4());
+        let quarter_tone_up = TunedNote {
+            cents: 50.0,
+            ..concert_a
+        };
+        let octave_down = TunedNote {
+            cents: -1200.0,
+            ..concert_a
+        };
+        assert!(quarter_tone_up.frequency() > concert_a.frequency());
+        assert!((octave_down.frequency() * 2.0 - concert_a.frequency()).abs() < 0.01);
+    }
+
+    #[test]
+    fn applies_deltas_after_keyframe() {
+        let mut sounding = SoundingNotes::default();
+        sounding.keyframe(&NotesKeyframe {
+            sequence: 1,
+            notes: HashSet::from([a4()]),
+        });
+        assert!(sounding.notes_on(&NotesDelta {
+            sequence: 2,
+            previous: 1,
+            notes: HashSet::from([c5()]),
+            velocity: None,
+        }));
+        assert!(sounding.notes_off(&NotesDelta {
+            sequence: 3,
+            previous: 2,
+            notes: HashSet::from([a4()]),
+            velocity: None,
+        }));
+        assert_eq!(sounding.notes(), &HashSet::from([c5()]));
+    }
+
+    #[test]
+    fn detects_missed_delta_until_keyframe() {
+        let mut sounding = SoundingNotes::default();
+        sounding.keyframe(&NotesKeyframe {
+            sequence: 1,
+            notes: HashSet::new(),
+        });
+        let late = NotesDelta {
+            sequence: 3,
+            previous: 2,
+            notes: HashSet::from([a4()]),
+            velocity: None,
+        };
+        assert!(!sounding.notes_on(&late));
+        assert!(!sounding.notes_on(&NotesDelta {
+            sequence: 4,
+            previous: 3,
+            notes: HashSet::from([c5()]),
+            velocity: None,
+        }));
+        sounding.keyframe(&NotesKeyframe {
+            sequence: 5,
+            notes: HashSet::from([c5()]),
+        });
+        assert_eq!(sounding.notes(), &HashSet::from([c5()]));
+    }
+}
@@ -4,7 +4,7 @@ use anyhow::Context;
 use clap::{command, Parser};
 use client_utils::{
     announce_as_consumer, announce_protocol_version, create_client, create_uri, create_watchdog,
-    flatten,
+    flatten, receive_text, Identification, Redirected,
 };
 use futures_util::SinkExt;
 use morivar::{ConsumerToServer, ServerToConsumer, ToMessage};
@@ -29,23 +29,32 @@ async fn main() -> anyhow::Result<()> {
 
     let args = Arguments::parse().args;
 
-    let uri = create_uri(args.url, args.secure)?;
+    let identification = Identification {
+        id: args.id,
+        room: args.room,
+        token: args.token,
+    };
+    let mut uri = create_uri(args.url, args.secure)?;
 
     loop {
-        let uri = uri.clone();
-        let id = args.id.clone();
+        let identification = identification.clone();
+        let connect_uri = uri.clone();
         // tokio::spawn to contain errors and panics, then wait, then rebuild
         let handle = tokio::spawn(async move {
             info!("Attempting to connect to server");
-            let mut stream = create_client(&uri, args.secure).await?;
+            let mut stream = create_client(&connect_uri, args.secure).await?;
 
-            if let Err(e) = pehnt(&mut stream, &id, args.pingpong).await {
-                warn!("Failed to handle connection: {e:?}");
-            }
-            anyhow::Ok(())
+            pehnt(&mut stream, &identification, args.pingpong)
+                .await
+                .context("Failed to handle connection")
         });
 
         if let Err(e) = flatten(handle).await {
+            if let Some(Redirected(address)) = e.downcast_ref() {
+                info!("Following redirect to {address}");
+                uri = create_uri(address.clone(), args.secure)?;
+                continue;
+            }
             warn!("{e:?}");
         }
 
@@ -54,32 +63,32 @@ async fn main() -> anyhow::Result<()> {
 }
 
 /// Handle the client connection
-async fn pehnt<S>(stream: &mut WebsocketStream<S>, id: &str, pingpong: bool) -> anyhow::Result<()>
+async fn pehnt<S>(
+    stream: &mut WebsocketStream<S>,
+    identification: &Identification,
+    pingpong: bool,
+) -> anyhow::Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    announce_protocol_version(stream).await?;
+    let heartbeat = announce_protocol_version(stream).await?;
 
-    announce_as_consumer(id, stream).await?;
+    announce_as_consumer(identification, stream).await?;
 
-    let (mut interval, watchdog, mut expiration) = create_watchdog().await?;
+    let (mut interval, watchdog, mut expiration) = create_watchdog(&heartbeat).await?;
 
     loop {
         select! {
-            msg = stream.next() => {
-                let Some(Ok(msg)) = msg else {
+            msg = receive_text(stream) => {
+                let Some(Ok(text)) = msg else {
                     warn!("Breaking on client message: {msg:?}");
                     break;
                 };
-                let Ok(text) = msg.as_text() else {
-                    warn!("Received non-text message, stopping receive");
-                    break;
-                };
                 if pingpong {
                     // on any message, even non-pong, stop the watchdog - the server is alive at least.
                     watchdog.send(Signal::Stop).await.context("Failed to reset the watchdog")?;
                 }
-                handle_message(text);
+                handle_message(&text)?;
             }
             _i = interval.tick(), if pingpong => {
                 info!("Sending Ping!");
@@ -97,7 +106,7 @@ where
     Ok(())
 }
 
-fn handle_message(text: &str) {
+fn handle_message(text: &str) -> anyhow::Result<()> {
     match serde_json::from_str(text) {
         Ok(ServerToConsumer::ChordEvent(chord)) => {
             info!("Chord: {chord}");
@@ -105,14 +114,40 @@ fn handle_message(text: &str) {
         Ok(ServerToConsumer::PitchesEvent(pitches)) => {
             info!("Pitches: {pitches:?}");
         }
+        Ok(ServerToConsumer::RecognizedPitchesEvent { pitches, chords }) => {
+            let names = chords.iter().map(ToString::to_string).collect::<Vec<_>>();
+            info!("Pitches: {pitches:?}, recognized as {}", names.join(", "));
+        }
+        Ok(ServerToConsumer::TunedPitchesEvent(pitches)) => {
+            info!("Tuned pitches: {pitches:?}");
+        }
+        Ok(ServerToConsumer::PitchBendEvent { cents }) => {
+            info!("Pitch bend: {cents} cents");
+        }
+        Ok(ServerToConsumer::NotesOn(delta)) => {
+            info!("Notes on: {delta:?}");
+        }
+        Ok(ServerToConsumer::NotesOff(delta)) => {
+            info!("Notes off: {delta:?}");
+        }
+        Ok(ServerToConsumer::Keyframe(keyframe)) => {
+            info!("Keyframe: {keyframe:?}");
+        }
         Ok(ServerToConsumer::Silence) => {
             info!("SILENCE!!!");
         }
         Ok(ServerToConsumer::Pong) => {
             info!("Received Pong!");
         }
+        Ok(ServerToConsumer::Redirect { address }) => {
+            return Err(Redirected::parse(&address)?.into());
+        }
+        Ok(ServerToConsumer::Missed { count }) => {
+            warn!("Fell behind, the server skipped {count} events");
+        }
         Err(e) => {
             warn!("Protocol error, expected ServerToConsumer: {e:?}");
         }
     }
+    Ok(())
 }
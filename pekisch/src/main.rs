@@ -3,12 +3,14 @@
 use std::{
     collections::HashSet,
     sync::{Arc, Condvar, Mutex},
+    time::Duration,
 };
 
 use anyhow::Context;
 use clap::{command, Parser};
 use client_utils::{
-    announce_as_publisher, announce_protocol_version, create_client, create_watchdog, flatten,
+    announce_as_publisher, announce_protocol_version, create_client_with_identity, create_uri,
+    create_watchdog, flatten, load_identity, receive_text, Identification, Redirected,
 };
 use futures_util::SinkExt;
 use klib::core::{
@@ -16,7 +18,7 @@ use klib::core::{
     note::{HasNoteId, Note},
 };
 use midly::MidiMessage;
-use morivar::{PublisherToServer, ServerToPublisher, ToMessage};
+use morivar::{NotesDelta, NotesKeyframe, PublisherToServer, ServerToPublisher, ToMessage};
 use simple_tokio_watchdog::{Expired, Signal};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
@@ -43,6 +45,28 @@ struct Arguments {
     /// MIDI channel capacity
     #[arg(long, default_value_t = 256)]
     midi_event_queue_length: usize,
+
+    /// Whether to publish note on/off deltas instead of the full set of notes on every key press
+    #[arg(long, default_value_t = false)]
+    deltas: bool,
+
+    /// The interval to publish the full set of notes at when publishing deltas
+    #[arg(long, default_value_t = Duration::from_secs(2).into(), value_parser = parse_keyframe_interval)]
+    keyframe_interval: humantime::Duration,
+
+    /// The range of the pitch bend wheel in either direction, in semitones
+    #[arg(long, default_value_t = 2.0)]
+    pitch_bend_range: f32,
+}
+
+/// Parses a keyframe interval, which `tokio::time::interval` requires to be non-zero.
+fn parse_keyframe_interval(text: &str) -> anyhow::Result<humantime::Duration> {
+    let interval: humantime::Duration = text.parse().context("Invalid keyframe interval")?;
+    anyhow::ensure!(
+        !interval.is_zero(),
+        "The keyframe interval must not be zero"
+    );
+    Ok(interval)
 }
 
 #[tokio::main]
@@ -52,13 +76,23 @@ async fn main() -> anyhow::Result<()> {
     let args = Arguments::parse();
     let device = args.device;
     let midi_event_queue_length = args.midi_event_queue_length;
+    let deltas = args.deltas;
+    let keyframe_interval = *args.keyframe_interval;
+    let pitch_bend_range = args.pitch_bend_range;
     let args = args.args;
 
-    let uri = client_utils::create_uri(args.url, args.secure)?;
+    let identity = load_identity(args.client_cert.as_deref(), args.client_key.as_deref())?;
+    let identification = Identification {
+        id: args.id,
+        room: args.room,
+        token: args.token,
+    };
+    let mut uri = create_uri(args.url, args.secure)?;
 
     loop {
-        let uri = uri.clone();
-        let id = args.id.clone();
+        let connect_uri = uri.clone();
+        let identification = identification.clone();
+        let identity = identity.clone();
 
         // tokio::spawn to contain errors and panics, then wait, then rebuild
         let handle = tokio::spawn(async move {
@@ -73,9 +107,21 @@ async fn main() -> anyhow::Result<()> {
             });
 
             info!("Attempting to connect to server");
-            let mut stream = create_client(&uri, args.secure).await?;
+            let mut stream =
+                create_client_with_identity(&connect_uri, args.secure, identity.as_ref()).await?;
 
-            pekisch(&mut stream, midi_rx, &id, args.pingpong).await?;
+            pekisch(
+                &mut stream,
+                midi_rx,
+                &identification,
+                args.pingpong,
+                Publishing {
+                    deltas,
+                    keyframe_interval,
+                    pitch_bend_range,
+                },
+            )
+            .await?;
 
             let lock = &pair2.0;
             let cvar = &pair2.1;
@@ -87,6 +133,11 @@ async fn main() -> anyhow::Result<()> {
         });
 
         if let Err(e) = flatten(handle).await {
+            if let Some(Redirected(address)) = e.downcast_ref() {
+                info!("Following redirect to {address}");
+                uri = create_uri(address.clone(), args.secure)?;
+                continue;
+            }
             warn!("{e:?}");
         }
 
@@ -94,54 +145,99 @@ async fn main() -> anyhow::Result<()> {
     }
 }
 
+/// How MIDI events are turned into published messages
+#[derive(Debug, Clone, Copy)]
+struct Publishing {
+    deltas: bool,
+    keyframe_interval: Duration,
+    /// In semitones
+    pitch_bend_range: f32,
+}
+
 /// Handle the client connection
 async fn pekisch<S>(
     stream: &mut WebsocketStream<S>,
     mut midi_rx: mpsc::Receiver<MidiMessage>,
-    id: &str,
+    identification: &Identification,
     pingpong: bool,
+    publishing: Publishing,
 ) -> anyhow::Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    announce_protocol_version(stream).await?;
+    let heartbeat = announce_protocol_version(stream).await?;
 
-    announce_as_publisher(id, stream).await?;
+    announce_as_publisher(identification, stream).await?;
 
     let mut notes = HashSet::new();
+    let mut sequence = 0;
+    let mut keyframes = tokio::time::interval(publishing.keyframe_interval);
 
-    let (mut interval, watchdog, mut expiration) = create_watchdog().await?;
+    let (mut interval, watchdog, mut expiration) = create_watchdog(&heartbeat).await?;
 
     loop {
         select! {
-            msg = stream.next() => {
+            msg = receive_text(stream) => {
                 let Some(Ok(msg)) = msg else {
                     anyhow::bail!("Error receiving message: {msg:?}");
                 };
-                let Ok(msg) = msg.as_text() else {
-                   anyhow::bail!("Expected text message, got: {msg:?}");
-                };
-                let Ok(ServerToPublisher::Pong) = serde_json::from_str(msg) else {
-                    anyhow::bail!("Expected Pong, got: {msg:?}");
-                };
-                watchdog.send(Signal::Stop).await.context("Failed to stop watchdog")?;
+                match serde_json::from_str(&msg) {
+                    Ok(ServerToPublisher::Pong) => {
+                        watchdog.send(Signal::Stop).await.context("Failed to stop watchdog")?;
+                    }
+                    Ok(ServerToPublisher::Redirect { address }) => {
+                        return Err(Redirected::parse(&address)?.into());
+                    }
+                    Ok(ServerToPublisher::FloorGranted) => info!("Got the floor, publishing"),
+                    Ok(ServerToPublisher::FloorLost) => {
+                        info!("Lost the floor, events are dropped until it is free");
+                    }
+                    Ok(ServerToPublisher::RateLimited) => {
+                        warn!("Publishing too fast, the server drops events over its rate limit");
+                    }
+                    _ => anyhow::bail!("Expected Pong, got: {msg:?}"),
+                }
             }
             event = midi_rx.recv() => {
                 let Some(event) = event else {
                     break;
                 };
+                if let MidiMessage::PitchBend { bend } = event {
+                    let cents = bend.as_f32() * publishing.pitch_bend_range * 100.0;
+                    stream.send(PublisherToServer::PublishPitchBend { cents }.to_message()).await?;
+                    continue;
+                }
+                let before = notes.clone();
+                let velocity = match event {
+                    MidiMessage::NoteOn { vel, .. } if vel != 0 => Some(vel.as_int()),
+                    _ => None,
+                };
                 handle_midi_event(event, &mut notes);
-                let message = if let Some(chord) =
+                let message = if publishing.deltas {
+                    let Some(delta) = delta(&before, &notes, velocity, &mut sequence) else {
+                        continue;
+                    };
+                    delta.to_message()
+                } else if let Some(chord) =
                     Chord::try_from_notes(notes.iter().copied().collect::<Vec<_>>().as_slice())
                         .ok()
                         .and_then(|chords| chords.first().cloned())
                 {
-                    PublisherToServer::PublishChord(chord).to_message()
+                    PublisherToServer::PublishChord { chord, duration: None }.to_message()
                 } else {
-                    PublisherToServer::PublishPitches(notes.clone()).to_message()
+                    PublisherToServer::PublishPitches {
+                        pitches: notes.clone(),
+                        duration: None,
+                    }
+                    .to_message()
                 };
                 stream.send(message).await?;
             }
+            _k = keyframes.tick(), if publishing.deltas => {
+                sequence += 1;
+                let keyframe = NotesKeyframe { sequence, notes: notes.clone() };
+                stream.send(PublisherToServer::PublishKeyframe(keyframe).to_message()).await?;
+            }
             _i = interval.tick(), if pingpong => {
                 info!("Sending Ping!");
                 watchdog.send(Signal::Reset).await?;
@@ -159,6 +255,34 @@ where
     Ok(())
 }
 
+/// Describes the change from `before` to `after` as a delta following on `sequence`,
+/// with started notes struck at `velocity`.
+fn delta(
+    before: &HashSet<Note>,
+    after: &HashSet<Note>,
+    velocity: Option<u8>,
+    sequence: &mut u64,
+) -> Option<PublisherToServer> {
+    let previous = *sequence;
+    let delta = |notes: HashSet<Note>, velocity| NotesDelta {
+        sequence: previous + 1,
+        previous,
+        notes,
+        velocity,
+    };
+    let on: HashSet<Note> = after.difference(before).copied().collect();
+    let off: HashSet<Note> = before.difference(after).copied().collect();
+    let message = if !on.is_empty() {
+        PublisherToServer::PublishNotesOn(delta(on, velocity))
+    } else if !off.is_empty() {
+        PublisherToServer::PublishNotesOff(delta(off, None))
+    } else {
+        return None;
+    };
+    *sequence += 1;
+    Some(message)
+}
+
 fn handle_midi_event(event: MidiMessage, notes: &mut HashSet<Note>) {
     match event {
         MidiMessage::NoteOn { key, vel } => {
@@ -12,14 +12,16 @@ fn on_midi(event: &[u8]) -> Option<MidiMessage> {
     let event = LiveEvent::parse(event).unwrap();
     match event {
         LiveEvent::Midi { message, .. } => match message {
-            msg @ (MidiMessage::NoteOn { .. } | MidiMessage::NoteOff { .. }) => Some(msg),
+            msg @ (MidiMessage::NoteOn { .. }
+            | MidiMessage::NoteOff { .. }
+            | MidiMessage::PitchBend { .. }) => Some(msg),
             _ => None,
         },
         _ => None,
     }
 }
 
-/// Forwards note-on and note-off events from the selected midi interface to `midi_tx`.
+/// Forwards note-on, note-off and pitch bend events from the selected midi interface to `midi_tx`.
 pub fn forward(
     midi_tx: mpsc::Sender<MidiMessage>,
     index: Option<usize>,
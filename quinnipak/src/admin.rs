@@ -0,0 +1,111 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use anyhow::Context;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use morivar::ServerToConsumer;
+use tokio::net::TcpListener;
+use tracing::info;
+
+use crate::{
+    connections::{ConnectionInfo, Connections},
+    metrics::METRICS,
+    room::Rooms,
+};
+
+/// Inspect and control a running quinnipak over HTTP.
+#[derive(Debug, Clone)]
+pub struct Admin {
+    rooms: Rooms,
+    connections: Connections,
+    ready: Arc<AtomicBool>,
+}
+
+impl Admin {
+    pub fn new(rooms: Rooms, connections: Connections) -> Self {
+        Self {
+            rooms,
+            connections,
+            ready: Arc::default(),
+        }
+    }
+
+    /// Mark the server ready (or not) to accept clients, as reported on `/ready`.
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    pub fn router(self) -> Router {
+        Router::new()
+            .route("/health", get(health))
+            .route("/metrics", get(metrics))
+            .route("/ready", get(ready))
+            .route("/connections", get(connections))
+            .route("/connections/:connection/kick", post(kick))
+            .route("/silence", post(silence_all))
+            .route("/rooms/:room/silence", post(silence_room))
+            .with_state(self)
+    }
+
+    pub async fn serve(self, listener: TcpListener) -> anyhow::Result<()> {
+        axum::serve(listener, self.router())
+            .await
+            .context("Failed to serve admin API")
+    }
+}
+
+async fn health() -> &'static str {
+    "OK"
+}
+
+async fn ready(State(admin): State<Admin>) -> (StatusCode, &'static str) {
+    if admin.ready.load(Ordering::Relaxed) {
+        (StatusCode::OK, "READY")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "NOT READY")
+    }
+}
+
+async fn metrics() -> Result<String, (StatusCode, String)> {
+    METRICS
+        .render()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}")))
+}
+
+async fn connections(State(admin): State<Admin>) -> Json<Vec<ConnectionInfo>> {
+    Json(admin.connections.list())
+}
+
+async fn kick(State(admin): State<Admin>, Path(connection): Path<u64>) -> StatusCode {
+    if admin.connections.kick(connection) {
+        info!("Kicking connection {connection}");
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn silence_all(State(admin): State<Admin>) -> StatusCode {
+    info!("Silencing all rooms");
+    for room in admin.rooms.all() {
+        // No subscribed consumers means nothing to silence.
+        let _ = room.broadcast(ServerToConsumer::Silence);
+    }
+    StatusCode::NO_CONTENT
+}
+
+async fn silence_room(State(admin): State<Admin>, Path(room): Path<String>) -> StatusCode {
+    let Some(room) = admin.rooms.get(&room) else {
+        return StatusCode::NOT_FOUND;
+    };
+    info!("Silencing room \"{}\"", room.name);
+    let _ = room.broadcast(ServerToConsumer::Silence);
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,185 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use ipnet::IpNet;
+
+/// Which addresses may connect, and how many connections they may hold.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AdmissionPolicy {
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    /// If not empty, only these networks may connect
+    pub allow: Vec<IpNet>,
+    /// These networks may never connect, even if allowed
+    pub deny: Vec<IpNet>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Rejected {
+    #[error("{0} is denied")]
+    Denied(IpAddr),
+    #[error("{0} is not in an allowed network")]
+    NotAllowed(IpAddr),
+    #[error("Already serving the maximum of {0} connections")]
+    TooManyConnections(usize),
+    #[error("{0} already holds the maximum of {1} connections")]
+    TooManyFromAddress(IpAddr, usize),
+}
+
+impl Rejected {
+    /// The label for this reason on [`crate::metrics::Metrics::rejected`].
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Rejected::Denied(_) => "denied",
+            Rejected::NotAllowed(_) => "not_allowed",
+            Rejected::TooManyConnections(_) => "max_connections",
+            Rejected::TooManyFromAddress(..) => "max_connections_per_ip",
+        }
+    }
+}
+
+/// Admits connections by their peer address, counting those it admitted.
+#[derive(Debug, Clone, Default)]
+pub struct Admission {
+    policy: Arc<AdmissionPolicy>,
+    counts: Arc<Mutex<Counts>>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    total: usize,
+    by_address: HashMap<IpAddr, usize>,
+}
+
+impl Admission {
+    pub fn new(policy: AdmissionPolicy) -> Self {
+        Self {
+            policy: Arc::new(policy),
+            counts: Arc::default(),
+        }
+    }
+
+    /// Admit a connection from `address`, which counts until the returned ticket drops.
+    pub fn admit(&self, address: IpAddr) -> Result<Admitted, Rejected> {
+        let address = address.to_canonical();
+        let policy = &self.policy;
+        if policy.deny.iter().any(|net| net.contains(&address)) {
+            return Err(Rejected::Denied(address));
+        }
+        if !policy.allow.is_empty() && !policy.allow.iter().any(|net| net.contains(&address)) {
+            return Err(Rejected::NotAllowed(address));
+        }
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(max) = policy.max_connections {
+            if counts.total >= max {
+                return Err(Rejected::TooManyConnections(max));
+            }
+        }
+        let from_address = counts.by_address.get(&address).copied().unwrap_or(0);
+        if let Some(max) = policy.max_connections_per_ip {
+            if from_address >= max {
+                return Err(Rejected::TooManyFromAddress(address, max));
+            }
+        }
+        counts.total += 1;
+        counts.by_address.insert(address, from_address + 1);
+        Ok(Admitted {
+            address: Some(address),
+            counts: self.counts.clone(),
+        })
+    }
+
+    /// Admit a connection over a Unix domain socket, which only counts towards the total.
+    pub fn admit_local(&self) -> Result<Admitted, Rejected> {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(max) = self.policy.max_connections {
+            if counts.total >= max {
+                return Err(Rejected::TooManyConnections(max));
+            }
+        }
+        counts.total += 1;
+        Ok(Admitted {
+            address: None,
+            counts: self.counts.clone(),
+        })
+    }
+}
+
+/// An admitted connection, uncounted on drop.
+#[derive(Debug)]
+pub struct Admitted {
+    address: Option<IpAddr>,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        let Some(address) = self.address else {
+            return;
+        };
+        if let Some(count) = counts.by_address.get_mut(&address) {
+            *count -= 1;
+            if *count == 0 {
+                counts.by_address.remove(&address);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn address(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn applies_deny_over_allow() {
+        let admission = Admission::new(AdmissionPolicy {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: vec!["10.0.0.66/32".parse().unwrap()],
+            ..AdmissionPolicy::default()
+        });
+        assert!(admission.admit(address("10.1.2.3")).is_ok());
+        assert!(admission.admit(address("::ffff:10.1.2.3")).is_ok());
+        assert_eq!(
+            admission.admit(address("10.0.0.66")).unwrap_err().reason(),
+            "denied"
+        );
+        assert_eq!(
+            admission
+                .admit(address("192.168.1.2"))
+                .unwrap_err()
+                .reason(),
+            "not_allowed"
+        );
+    }
+
+    #[test]
+    fn caps_connections_until_dropped() {
+        let admission = Admission::new(AdmissionPolicy {
+            max_connections: Some(3),
+            max_connections_per_ip: Some(2),
+            ..AdmissionPolicy::default()
+        });
+        let first = admission.admit(address("10.0.0.1")).unwrap();
+        let _second = admission.admit(address("10.0.0.1")).unwrap();
+        assert_eq!(
+            admission.admit(address("10.0.0.1")).unwrap_err(),
+            Rejected::TooManyFromAddress(address("10.0.0.1"), 2)
+        );
+        let _third = admission.admit(address("10.0.0.2")).unwrap();
+        assert_eq!(
+            admission.admit(address("10.0.0.3")).unwrap_err(),
+            Rejected::TooManyConnections(3)
+        );
+        drop(first);
+        assert!(admission.admit(address("10.0.0.1")).is_ok());
+    }
+}
@@ -0,0 +1,303 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context;
+use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::connections::Role;
+
+/// The claims of a client token, signed with HS256 by one of the configured keys.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    /// The authenticated id
+    pub sub: String,
+    /// Expiry in seconds since the Unix epoch
+    pub exp: u64,
+    /// The only room the token is valid for, any room if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// The only role the token is valid for, `publisher` or `consumer`, any role if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+}
+
+/// How clients have to authenticate when identifying.
+#[derive(Clone, Default)]
+pub struct AuthPolicy {
+    /// Keys to verify tokens with, tokens are not checked if empty
+    token_keys: Arc<Vec<DecodingKey>>,
+    /// Whether publishers need a verified client certificate
+    pub publisher_certificate_required: bool,
+    /// Whether consumers may identify without a token while tokens are checked
+    pub anonymous_consumers: bool,
+    /// The only role clients may identify as, any role if missing
+    pub role: Option<Role>,
+}
+
+impl AuthPolicy {
+    pub fn new(
+        token_keys: Vec<DecodingKey>,
+        publisher_certificate_required: bool,
+        anonymous_consumers: bool,
+    ) -> Self {
+        Self {
+            token_keys: Arc::new(token_keys),
+            publisher_certificate_required,
+            anonymous_consumers,
+            role: None,
+        }
+    }
+
+    /// Load the shared secrets to verify tokens with, one per file.
+    pub fn load_token_keys(paths: &[impl AsRef<Path>]) -> anyhow::Result<Vec<DecodingKey>> {
+        paths
+            .iter()
+            .map(|path| {
+                let path = path.as_ref();
+                let secret = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read token key {path:?}"))?;
+                let secret = secret.trim();
+                anyhow::ensure!(!secret.is_empty(), "Token key {path:?} is empty");
+                Ok(DecodingKey::from_secret(secret.as_bytes()))
+            })
+            .collect()
+    }
+
+    fn verify(&self, token: &str) -> Result<Claims, Unauthorized> {
+        let validation = Validation::new(Algorithm::HS256);
+        for key in self.token_keys.iter() {
+            match jsonwebtoken::decode::<Claims>(token, key, &validation) {
+                Ok(data) => return Ok(data.claims),
+                // Signed by another key, maybe.
+                Err(e) if matches!(e.kind(), ErrorKind::InvalidSignature) => continue,
+                Err(e) => return Err(Unauthorized::InvalidToken(e)),
+            }
+        }
+        Err(Unauthorized::InvalidToken(
+            ErrorKind::InvalidSignature.into(),
+        ))
+    }
+}
+
+/// What a client proved about itself on connecting, and what it has to prove to identify.
+#[derive(Clone, Default)]
+pub struct Authentication {
+    /// The subject of the client's verified TLS certificate
+    pub certificate_subject: Option<String>,
+    pub policy: AuthPolicy,
+}
+
+/// A client may not identify the way it tried to.
+#[derive(Debug, thiserror::Error)]
+pub enum Unauthorized {
+    #[error("Publisher \"{0}\" presented no verified client certificate")]
+    MissingCertificate(String),
+    #[error("Client \"{0}\" presented no token")]
+    MissingToken(String),
+    #[error("Invalid token: {0}")]
+    InvalidToken(#[source] jsonwebtoken::errors::Error),
+    #[error("Token is not valid for room \"{0}\"")]
+    WrongRoom(String),
+    #[error("Token is not valid for {} clients", .0.label())]
+    WrongRole(Role),
+    #[error("This listener does not accept {} clients", .0.label())]
+    RoleNotAccepted(Role),
+}
+
+impl Authentication {
+    /// Check a client identifying as `claimed` in `room`, returning its authenticated id.
+    ///
+    /// A certificate subject takes precedence over a token subject, which takes precedence over the claimed id.
+    /// Only tokens grant rooms and roles, so while tokens are checked, clients with a certificate need one too.
+    pub fn identify(
+        &self,
+        role: Role,
+        claimed: String,
+        room: &str,
+        token: Option<&str>,
+    ) -> Result<String, Unauthorized> {
+        if self.policy.role.is_some_and(|accepted| accepted != role) {
+            return Err(Unauthorized::RoleNotAccepted(role));
+        }
+        let certified = match (role, &self.certificate_subject) {
+            (Role::Publisher, Some(subject)) => Some(subject.clone()),
+            (Role::Publisher, None) if self.policy.publisher_certificate_required => {
+                return Err(Unauthorized::MissingCertificate(claimed))
+            }
+            _ => None,
+        };
+        let anonymous = role == Role::Consumer && self.policy.anonymous_consumers;
+        if self.policy.token_keys.is_empty() || (anonymous && token.is_none()) {
+            return Ok(certified.unwrap_or(claimed));
+        }
+        let token = token.ok_or_else(|| Unauthorized::MissingToken(claimed.clone()))?;
+        let claims = self.policy.verify(token)?;
+        if claims.room.as_ref().is_some_and(|scope| scope != room) {
+            return Err(Unauthorized::WrongRoom(room.to_string()));
+        }
+        if claims
+            .role
+            .as_ref()
+            .is_some_and(|scope| scope != role.label())
+        {
+            return Err(Unauthorized::WrongRole(role));
+        }
+        Ok(certified.unwrap_or(claims.sub))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use jsonwebtoken::{EncodingKey, Header};
+
+    use super::*;
+
+    const SECRET: &[u8] = b"correct horse battery staple";
+
+    fn token(secret: &[u8], room: Option<&str>, expires_in: i64) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let claims = Claims {
+            sub: "singer".to_string(),
+            exp: (now.as_secs() as i64 + expires_in) as u64,
+            room: room.map(ToString::to_string),
+            role: Some("publisher".to_string()),
+        };
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap()
+    }
+
+    fn authentication(anonymous_consumers: bool) -> Authentication {
+        Authentication {
+            certificate_subject: None,
+            policy: AuthPolicy::new(
+                vec![
+                    DecodingKey::from_secret(b"old key"),
+                    DecodingKey::from_secret(SECRET),
+                ],
+                false,
+                anonymous_consumers,
+            ),
+        }
+    }
+
+    #[test]
+    fn certificate_subject_overrides_claimed_id() {
+        let authentication = Authentication {
+            certificate_subject: Some("singer".to_string()),
+            policy: AuthPolicy::new(vec![], true, false),
+        };
+        assert_eq!(
+            authentication
+                .identify(Role::Publisher, "anyone".to_string(), "default", None)
+                .unwrap(),
+            "singer"
+        );
+
+        let anonymous = Authentication {
+            certificate_subject: None,
+            ..authentication
+        };
+        assert!(matches!(
+            anonymous.identify(Role::Publisher, "anyone".to_string(), "default", None),
+            Err(Unauthorized::MissingCertificate(_))
+        ));
+        assert_eq!(
+            Authentication::default()
+                .identify(Role::Publisher, "anyone".to_string(), "default", None)
+                .unwrap(),
+            "anyone"
+        );
+    }
+
+    #[test]
+    fn restricts_roles() {
+        let authentication = Authentication {
+            certificate_subject: Some("singer".to_string()),
+            policy: AuthPolicy {
+                role: Some(Role::Consumer),
+                ..AuthPolicy::default()
+            },
+        };
+        assert!(matches!(
+            authentication.identify(Role::Publisher, "anyone".to_string(), "default", None),
+            Err(Unauthorized::RoleNotAccepted(Role::Publisher))
+        ));
+        assert!(authentication
+            .identify(Role::Consumer, "anyone".to_string(), "default", None)
+            .is_ok());
+    }
+
+    #[test]
+    fn verifies_tokens() {
+        let authentication = authentication(false);
+        let identify = |token: &str, room| {
+            authentication.identify(Role::Publisher, "anyone".to_string(), room, Some(token))
+        };
+        assert_eq!(
+            identify(&token(SECRET, Some("choir"), 60), "choir").unwrap(),
+            "singer"
+        );
+        assert!(matches!(
+            identify(&token(SECRET, Some("choir"), 60), "band"),
+            Err(Unauthorized::WrongRoom(_))
+        ));
+        assert!(matches!(
+            identify(&token(SECRET, None, -3600), "choir"),
+            Err(Unauthorized::InvalidToken(_))
+        ));
+        assert!(matches!(
+            identify(&token(b"forged", None, 60), "choir"),
+            Err(Unauthorized::InvalidToken(_))
+        ));
+        assert!(matches!(
+            authentication.identify(
+                Role::Consumer,
+                "listener".to_string(),
+                "choir",
+                Some(&token(SECRET, None, 60))
+            ),
+            Err(Unauthorized::WrongRole(Role::Consumer))
+        ));
+    }
+
+    #[test]
+    fn scopes_certified_publishers_to_their_token() {
+        let authentication = Authentication {
+            certificate_subject: Some("soloist".to_string()),
+            ..authentication(false)
+        };
+        let identify = |token: Option<&str>, room| {
+            authentication.identify(Role::Publisher, "anyone".to_string(), room, token)
+        };
+        let token = token(SECRET, Some("choir"), 60);
+        assert_eq!(identify(Some(&token), "choir").unwrap(), "soloist");
+        assert!(matches!(
+            identify(Some(&token), "band"),
+            Err(Unauthorized::WrongRoom(_))
+        ));
+        assert!(matches!(
+            identify(None, "choir"),
+            Err(Unauthorized::MissingToken(_))
+        ));
+    }
+
+    #[test]
+    fn consumers_stay_anonymous_if_allowed() {
+        assert!(matches!(
+            authentication(false).identify(Role::Consumer, "listener".to_string(), "choir", None),
+            Err(Unauthorized::MissingToken(_))
+        ));
+        assert_eq!(
+            authentication(true)
+                .identify(Role::Consumer, "listener".to_string(), "choir", None)
+                .unwrap(),
+            "listener"
+        );
+    }
+}
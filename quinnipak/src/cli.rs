@@ -1,24 +1,240 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf};
 
-use crate::secure::SecurityMode;
-use clap::Parser;
+use crate::{rate_limit::RateLimitAction, secure::SecurityMode};
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use http::uri::Authority;
+use ipnet::IpNet;
+use morivar::Heartbeat;
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Clone, Parser)]
 #[command(author, version)]
 pub struct Arguments {
-    /// The address to bind on
-    #[arg(short, long, default_value = "0.0.0.0:8000")]
-    pub address: SocketAddr,
+    /// A TOML configuration file, command line arguments take precedence over it
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
 
-    /// The security mode
+    /// Only check the configuration, then exit
+    #[arg(long, default_value_t = false)]
+    pub check_config: bool,
+
+    /// The address to bind on [default: 0.0.0.0:8000, unless the configuration file has `listeners`]
+    #[arg(short, long)]
+    pub address: Option<SocketAddr>,
+
+    /// The address to bind on for raw TCP clients, which speak newline-delimited JSON or text commands
+    #[arg(long)]
+    pub tcp_address: Option<SocketAddr>,
+
+    /// A Unix domain socket to listen on for websocket clients, like local bridges
+    #[arg(long)]
+    pub unix_socket: Option<PathBuf>,
+
+    /// A Unix domain socket to listen on for raw clients, like `--tcp-address`
+    #[arg(long)]
+    pub lines_unix_socket: Option<PathBuf>,
+
+    /// The address to serve the admin HTTP API on, e.g. `127.0.0.1:8080`
+    #[arg(long)]
+    pub admin_address: Option<SocketAddr>,
+
+    /// The security mode, or a replay
     #[command(subcommand)]
-    pub mode: Option<SecurityMode>,
+    pub mode: Option<Mode>,
+
+    /// A file holding a shared secret to verify client tokens with, may be repeated to rotate keys
+    #[arg(long)]
+    pub token_key_file: Vec<PathBuf>,
+
+    /// Let consumers identify without a token while tokens are checked
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub anonymous_consumers: Option<bool>,
+
+    /// How many connections to serve at once
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+
+    /// How many connections to serve at once from one address
+    #[arg(long)]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Only accept connections from this network, e.g. `10.0.0.0/8`, may be repeated
+    #[arg(long)]
+    pub allow: Vec<IpNet>,
+
+    /// Refuse connections from this network, even if allowed, may be repeated
+    #[arg(long)]
+    pub deny: Vec<IpNet>,
+
+    /// Only accept browser pages from this origin, e.g. `https://horeau.example`, may be repeated
+    #[arg(long)]
+    pub allowed_origin: Vec<String>,
+
+    /// Serve this directory to browsers instead of the built-in page, e.g. horeau's `dist`
+    #[arg(long)]
+    pub web_root: Option<PathBuf>,
+
+    /// How many events to queue for each consumer [default: 64]
+    #[arg(long)]
+    pub chords_channel_size: Option<usize>,
+
+    /// Attach recognized chords to pitch events in all rooms
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub recognize_chords: Option<bool>,
+
+    /// Limit each publisher to this many events per second, silence is never limited
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rate_limit: Option<u32>,
+
+    /// How many events a publisher may send at once, defaults to the rate limit
+    #[arg(long, requires = "rate_limit")]
+    pub rate_limit_burst: Option<u32>,
+
+    /// What to do with events over the rate limit [default: drop]
+    #[arg(long, value_enum, requires = "rate_limit")]
+    pub rate_limit_action: Option<RateLimitAction>,
+
+    /// Record each room's sessions to a file in this directory
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+
+    /// Relay rooms from this quinnipak, like `central.local:8000`
+    #[arg(long)]
+    pub upstream: Option<Authority>,
+
+    /// A room to relay from upstream into the local room of the same name, may be repeated [default: the default room]
+    #[arg(long)]
+    pub upstream_room: Vec<String>,
 
-    /// The channel size for the chord broadcast
-    #[arg(long, default_value_t = 64)]
-    pub chords_channel_size: usize,
+    /// Connect to the upstream quinnipak with TLS
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub upstream_secure: Option<bool>,
+
+    /// A file holding the token to identify with upstream
+    #[arg(long)]
+    pub upstream_token_file: Option<PathBuf>,
+
+    /// Identifies this server to relaying servers, to detect loops [default: random]
+    #[arg(long)]
+    pub server_id: Option<String>,
 
     /// Whether to monitor consumers for pings
-    #[arg(long, default_value_t = false)]
-    pub pingpong: bool,
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub pingpong: Option<bool>,
+
+    /// The heartbeat announced to websocket clients, as `<ping interval>/<ping await interval>/<pong allowed delay>` [default: 10s/15s/5s]
+    #[arg(long, value_parser = parse_heartbeat)]
+    pub heartbeat: Option<Heartbeat>,
+
+    /// The heartbeat announced to raw TCP clients, defaults to the websocket heartbeat
+    #[arg(long, value_parser = parse_heartbeat)]
+    pub tcp_heartbeat: Option<Heartbeat>,
+
+    /// On shutdown, redirect clients to the server at this address (`host:port`), e.g. during upgrades
+    #[arg(long)]
+    pub drain_to: Option<Authority>,
+
+    /// How long to wait for clients to leave on shutdown [default: 5s]
+    #[arg(long)]
+    pub shutdown_timeout: Option<humantime::Duration>,
+
+    /// How long clients may take to upgrade to websocket, and then to identify [default: 10s]
+    #[arg(long)]
+    pub handshake_timeout: Option<humantime::Duration>,
+
+    /// How long clients may take for the TLS handshake [default: 10s]
+    #[arg(long)]
+    pub tls_timeout: Option<humantime::Duration>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Mode {
+    #[command(flatten)]
+    Security(SecurityMode),
+    /// Serve as usual, and play a recorded session into a room once a consumer joins it
+    Replay {
+        /// A session recorded with `--record`
+        file: PathBuf,
+
+        /// The room to play into, defaults to the recorded room
+        #[arg(long)]
+        room: Option<String>,
+
+        /// How many times as fast as recorded to play, e.g. `0.5` for half speed
+        #[arg(long, default_value_t = 1.0, value_parser = parse_speed)]
+        speed: f64,
+    },
+}
+
+/// Parses a positive replay speed.
+pub fn parse_speed(text: &str) -> anyhow::Result<f64> {
+    let speed: f64 = text.parse().context("Invalid speed")?;
+    anyhow::ensure!(
+        speed.is_finite() && speed > 0.0,
+        "The speed must be positive"
+    );
+    Ok(speed)
+}
+
+/// Parses a heartbeat like `10s/15s/5s`.
+pub fn parse_heartbeat(text: &str) -> anyhow::Result<Heartbeat> {
+    let durations = text
+        .split('/')
+        .map(|duration| humantime::parse_duration(duration.trim()))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Invalid duration in heartbeat")?;
+    let [ping_interval, ping_await_interval, ping_to_pong_allowed_delay] = durations[..] else {
+        anyhow::bail!("Expected <ping interval>/<ping await interval>/<pong allowed delay>");
+    };
+    anyhow::ensure!(
+        !durations.iter().any(|duration| duration.is_zero()),
+        "Heartbeat durations must not be zero"
+    );
+    anyhow::ensure!(
+        ping_interval < ping_await_interval,
+        "The ping interval must be shorter than the ping await interval"
+    );
+    Ok(Heartbeat {
+        ping_interval,
+        ping_await_interval,
+        ping_to_pong_allowed_delay,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_default_heartbeat() {
+        assert_eq!(parse_heartbeat("10s/15s/5s").unwrap(), Heartbeat::default());
+    }
+
+    #[test]
+    fn rejects_zero_heartbeat() {
+        assert!(parse_heartbeat("0s/15s/5s").is_err());
+        assert!(parse_heartbeat("10s/15s/0s").is_err());
+    }
+
+    #[test]
+    fn parses_replay() {
+        let args = Arguments::parse_from(["quinnipak", "replay", "choir.jsonl", "--speed", "2"]);
+        assert!(matches!(
+            args.mode,
+            Some(Mode::Replay { speed, room: None, .. }) if speed == 2.0
+        ));
+        assert!(
+            Arguments::try_parse_from(["quinnipak", "replay", "choir.jsonl", "--speed", "0"])
+                .is_err()
+        );
+        let args =
+            Arguments::parse_from(["quinnipak", "secure", "--cert", "c.pem", "--key", "k.pem"]);
+        assert!(matches!(args.mode, Some(Mode::Security(_))));
+    }
+
+    #[test]
+    fn rejects_heartbeat_which_cuts_clients() {
+        assert!(parse_heartbeat("20s/15s/5s").is_err());
+        assert!(parse_heartbeat("10s/15s").is_err());
+    }
 }
@@ -0,0 +1,998 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use http::uri::Authority;
+use ipnet::IpNet;
+use morivar::Heartbeat;
+use serde::Deserialize;
+use tokio::{
+    select,
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+use tracing::{info, warn};
+
+use crate::{
+    admission::AdmissionPolicy,
+    cli::{parse_heartbeat, Arguments, Mode},
+    connections::Role,
+    floor::FloorPolicy,
+    origin::Origins,
+    rate_limit::RateLimit,
+    room::RoomSettings,
+    secure::SecurityMode,
+    web::Site,
+    Timing,
+};
+
+/// How often to check the configuration file for changes.
+pub(crate) const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The configuration file, layered under the command line arguments.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub address: Option<SocketAddr>,
+    pub tcp_address: Option<SocketAddr>,
+    pub unix_socket: Option<PathBuf>,
+    pub lines_unix_socket: Option<PathBuf>,
+    pub admin_address: Option<SocketAddr>,
+    pub chords_channel_size: Option<usize>,
+    pub recognize_chords: Option<bool>,
+    /// The rate limit of each publisher
+    pub rate_limit: Option<RateLimit>,
+    /// The directory to record sessions to
+    pub record: Option<PathBuf>,
+    pub pingpong: Option<bool>,
+    /// Like `10s/15s/5s`
+    pub heartbeat: Option<String>,
+    pub tcp_heartbeat: Option<String>,
+    /// Like `backup:8000`
+    pub drain_to: Option<String>,
+    pub server_id: Option<String>,
+    /// Another quinnipak to relay rooms from
+    pub upstream: Option<UpstreamConfig>,
+    /// Like `5s`
+    pub shutdown_timeout: Option<String>,
+    /// Like `10s`
+    pub handshake_timeout: Option<String>,
+    /// Like `10s`
+    pub tls_timeout: Option<String>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    /// Networks like `10.0.0.0/8`, if not empty only these may connect
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// Networks which may not connect
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+    /// Origins like `https://horeau.example` whose pages may connect
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// The directory to serve to browsers instead of the built-in page
+    pub web_root: Option<PathBuf>,
+    pub tls: Option<Tls>,
+    pub auth: Option<Auth>,
+    /// Websocket listeners besides `address`
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// Settings for rooms by name
+    #[serde(default)]
+    pub rooms: HashMap<String, RoomConfig>,
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {path:?}"))?;
+        toml::from_str(&text).with_context(|| format!("Invalid config file {path:?}"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA certificates to verify client certificates against
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    #[serde(default)]
+    pub require_client_cert_for_publishers: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Auth {
+    /// Files holding shared secrets to verify client tokens with
+    #[serde(default)]
+    pub token_key_files: Vec<PathBuf>,
+    #[serde(default)]
+    pub anonymous_consumers: bool,
+}
+
+/// Another quinnipak to relay rooms from.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// Like `central.local:8000`
+    pub address: Option<String>,
+    #[serde(default)]
+    pub secure: bool,
+    /// The rooms to relay, each into the local room of the same name
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    /// A file holding the token to identify with upstream
+    pub token_file: Option<PathBuf>,
+    /// A client certificate to present upstream
+    pub client_cert: Option<PathBuf>,
+    /// The PKCS#8 key of `client_cert`, RSA and EC keys have to be converted
+    pub client_key: Option<PathBuf>,
+}
+
+/// A websocket listener with its own security and heartbeat.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    pub tls: Option<Tls>,
+    /// Like `20s/45s/15s`, overrides the server-wide `heartbeat` for this listener
+    pub heartbeat: Option<String>,
+    /// `publisher` or `consumer`, the only role clients may identify as here
+    pub role: Option<Role>,
+}
+
+impl ListenerConfig {
+    /// The listener's settings, and its heartbeat which may change while quinnipak runs.
+    fn resolve(self) -> anyhow::Result<(ListenerSettings, Option<Heartbeat>)> {
+        check_tls(self.tls.as_ref())?;
+        let heartbeat = self
+            .heartbeat
+            .map(|text| parse_heartbeat(&text).context("Invalid heartbeat"))
+            .transpose()?;
+        let settings = ListenerSettings {
+            address: self.address,
+            tls: self.tls,
+            role: self.role,
+        };
+        Ok((settings, heartbeat))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoomConfig {
+    #[serde(default)]
+    pub floor: Floor,
+    /// Like `30s`, how long a quiet publisher keeps the floor
+    pub floor_timeout: Option<String>,
+    /// Publisher ids by descending priority, for the `priority` floor
+    #[serde(default)]
+    pub priority: Vec<String>,
+    /// Broadcast the union of all publishers' sounding notes
+    #[serde(default)]
+    pub ensemble: bool,
+    /// Overrides the server-wide `recognize_chords` for this room
+    pub recognize_chords: Option<bool>,
+    /// Overrides the server-wide `rate_limit` for this room
+    pub rate_limit: Option<RateLimit>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Floor {
+    #[default]
+    FreeForAll,
+    Exclusive,
+    Priority,
+}
+
+impl RoomConfig {
+    fn resolve(self, defaults: &RoomSettings) -> anyhow::Result<RoomSettings> {
+        let timeout = match self.floor_timeout {
+            Some(text) => humantime::parse_duration(&text).context("Invalid floor timeout")?,
+            None => Duration::from_secs(30),
+        };
+        let floor = match self.floor {
+            Floor::FreeForAll => FloorPolicy::FreeForAll,
+            Floor::Exclusive => FloorPolicy::Exclusive { timeout },
+            Floor::Priority => FloorPolicy::Priority {
+                order: self.priority,
+                timeout,
+            },
+        };
+        check_rate_limit(self.rate_limit.as_ref())?;
+        Ok(RoomSettings {
+            floor,
+            ensemble: self.ensemble,
+            recognize_chords: self.recognize_chords.unwrap_or(defaults.recognize_chords),
+            rate_limit: self.rate_limit.or(defaults.rate_limit),
+            record: defaults.record.clone(),
+        })
+    }
+}
+
+fn check_tls(tls: Option<&Tls>) -> anyhow::Result<()> {
+    if let Some(tls) = tls {
+        anyhow::ensure!(
+            tls.client_ca.is_some() || !tls.require_client_cert_for_publishers,
+            "Requiring client certificates for publishers needs a client CA"
+        );
+    }
+    Ok(())
+}
+
+fn check_rate_limit(rate_limit: Option<&RateLimit>) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !matches!(
+            rate_limit,
+            Some(RateLimit {
+                events_per_second: 0,
+                ..
+            })
+        ),
+        "A rate limit needs to allow at least one event per second"
+    );
+    Ok(())
+}
+
+/// The effective settings, from command line arguments over the configuration file over defaults.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// Not listened on if only `listeners` are configured
+    pub address: Option<SocketAddr>,
+    pub tcp_address: Option<SocketAddr>,
+    pub unix_socket: Option<PathBuf>,
+    pub lines_unix_socket: Option<PathBuf>,
+    pub admin_address: Option<SocketAddr>,
+    pub tls: Option<Tls>,
+    pub auth: Auth,
+    pub admission: AdmissionPolicy,
+    pub origins: Origins,
+    pub site: Site,
+    pub listeners: Vec<ListenerSettings>,
+    /// Random if missing
+    pub server_id: Option<String>,
+    pub upstream: Option<Upstream>,
+    /// Settings for rooms without their own
+    pub room_defaults: RoomSettings,
+    pub rooms: HashMap<String, RoomSettings>,
+    pub chords_channel_size: usize,
+    pub runtime: Runtime,
+}
+
+/// Another quinnipak to relay rooms from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Upstream {
+    pub address: Authority,
+    pub secure: bool,
+    /// The rooms to relay, each into the local room of the same name
+    pub rooms: Vec<String>,
+    pub token_file: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+/// A websocket listener besides `address`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenerSettings {
+    pub address: SocketAddr,
+    pub tls: Option<Tls>,
+    /// The only role clients may identify as, any role if missing
+    pub role: Option<Role>,
+}
+
+/// The settings which can change while quinnipak runs.
+///
+/// Changes apply to clients connecting afterwards, and to the next shutdown.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Runtime {
+    pub pingpong: bool,
+    pub heartbeat: Heartbeat,
+    pub tcp_heartbeat: Heartbeat,
+    /// Overrides the heartbeat for the listeners at these addresses
+    pub listener_heartbeats: HashMap<SocketAddr, Heartbeat>,
+    pub drain_to: Option<Authority>,
+    pub shutdown_timeout: Duration,
+    pub handshake_timeout: Duration,
+    pub tls_timeout: Duration,
+}
+
+impl Settings {
+    pub fn resolve(args: &Arguments, config: Config) -> anyhow::Result<Self> {
+        let heartbeat = match (args.heartbeat, config.heartbeat) {
+            (Some(heartbeat), _) => heartbeat,
+            (None, Some(text)) => parse_heartbeat(&text).context("Invalid heartbeat in config")?,
+            (None, None) => Heartbeat::default(),
+        };
+        let tcp_heartbeat = match (args.tcp_heartbeat, config.tcp_heartbeat) {
+            (Some(heartbeat), _) => heartbeat,
+            (None, Some(text)) => {
+                parse_heartbeat(&text).context("Invalid TCP heartbeat in config")?
+            }
+            (None, None) => heartbeat,
+        };
+        let drain_to = match (&args.drain_to, config.drain_to) {
+            (Some(address), _) => Some(address.clone()),
+            (None, Some(text)) => Some(text.parse().context("Invalid drain address in config")?),
+            (None, None) => None,
+        };
+        let upstream_config = config.upstream.unwrap_or_default();
+        let upstream = match (&args.upstream, upstream_config.address) {
+            (Some(address), _) => Some(address.clone()),
+            (None, Some(text)) => Some(text.parse().context("Invalid upstream address in config")?),
+            (None, None) => None,
+        };
+        let upstream = upstream.map(|address| {
+            let rooms = match (&args.upstream_room[..], upstream_config.rooms) {
+                ([], rooms) if rooms.is_empty() => vec![morivar::DEFAULT_ROOM.to_string()],
+                ([], rooms) => rooms,
+                (rooms, _) => rooms.to_vec(),
+            };
+            Upstream {
+                address,
+                secure: args.upstream_secure.unwrap_or(upstream_config.secure),
+                rooms,
+                token_file: args
+                    .upstream_token_file
+                    .clone()
+                    .or(upstream_config.token_file),
+                client_cert: upstream_config.client_cert,
+                client_key: upstream_config.client_key,
+            }
+        });
+        if let Some(upstream) = &upstream {
+            anyhow::ensure!(
+                upstream.client_cert.is_some() == upstream.client_key.is_some(),
+                "An upstream client certificate needs a key, and the other way round"
+            );
+        }
+        let shutdown_timeout = duration(args.shutdown_timeout, config.shutdown_timeout, 5)
+            .context("Invalid shutdown timeout in config")?;
+        let handshake_timeout = duration(args.handshake_timeout, config.handshake_timeout, 10)
+            .context("Invalid handshake timeout in config")?;
+        let tls_timeout = duration(args.tls_timeout, config.tls_timeout, 10)
+            .context("Invalid TLS timeout in config")?;
+        let tls = match &args.mode {
+            Some(Mode::Security(SecurityMode::Secure {
+                cert,
+                key,
+                client_ca,
+                require_client_cert_for_publishers,
+            })) => Some(Tls {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: client_ca.clone(),
+                require_client_cert_for_publishers: *require_client_cert_for_publishers,
+            }),
+            Some(Mode::Replay { .. }) | None => config.tls,
+        };
+        check_tls(tls.as_ref())?;
+        let mut listener_heartbeats = HashMap::new();
+        let listeners: Vec<_> = config
+            .listeners
+            .into_iter()
+            .map(|listener| {
+                let address = listener.address;
+                let (settings, heartbeat) = listener
+                    .resolve()
+                    .with_context(|| format!("Invalid settings for listener {address}"))?;
+                if let Some(heartbeat) = heartbeat {
+                    listener_heartbeats.insert(address, heartbeat);
+                }
+                Ok(settings)
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let config_auth = config.auth.unwrap_or_default();
+        let auth = Auth {
+            token_key_files: if args.token_key_file.is_empty() {
+                config_auth.token_key_files
+            } else {
+                args.token_key_file.clone()
+            },
+            anonymous_consumers: args
+                .anonymous_consumers
+                .unwrap_or(config_auth.anonymous_consumers),
+        };
+        let admission = AdmissionPolicy {
+            max_connections: args.max_connections.or(config.max_connections),
+            max_connections_per_ip: args
+                .max_connections_per_ip
+                .or(config.max_connections_per_ip),
+            allow: if args.allow.is_empty() {
+                config.allow
+            } else {
+                args.allow.clone()
+            },
+            deny: if args.deny.is_empty() {
+                config.deny
+            } else {
+                args.deny.clone()
+            },
+        };
+        let origins = Origins {
+            allowed: if args.allowed_origin.is_empty() {
+                config.allowed_origins
+            } else {
+                args.allowed_origin.clone()
+            },
+        };
+        let rate_limit = match args.rate_limit {
+            Some(events_per_second) => Some(RateLimit {
+                events_per_second,
+                burst: args.rate_limit_burst,
+                action: args.rate_limit_action.unwrap_or_default(),
+            }),
+            None => config.rate_limit,
+        };
+        check_rate_limit(rate_limit.as_ref())?;
+        let room_defaults = RoomSettings {
+            recognize_chords: args
+                .recognize_chords
+                .or(config.recognize_chords)
+                .unwrap_or(false),
+            rate_limit,
+            record: args.record.clone().or(config.record),
+            ..RoomSettings::default()
+        };
+        let rooms = config
+            .rooms
+            .into_iter()
+            .map(|(name, room)| {
+                let settings = room
+                    .resolve(&room_defaults)
+                    .with_context(|| format!("Invalid settings for room \"{name}\""))?;
+                Ok((name, settings))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            address: args.address.or(config.address).or_else(|| {
+                listeners
+                    .is_empty()
+                    .then(|| SocketAddr::from(([0, 0, 0, 0], 8000)))
+            }),
+            tcp_address: args.tcp_address.or(config.tcp_address),
+            unix_socket: args.unix_socket.clone().or(config.unix_socket),
+            lines_unix_socket: args.lines_unix_socket.clone().or(config.lines_unix_socket),
+            admin_address: args.admin_address.or(config.admin_address),
+            tls,
+            auth,
+            admission,
+            origins,
+            site: Site {
+                root: args.web_root.clone().or(config.web_root),
+            },
+            listeners,
+            server_id: args.server_id.clone().or(config.server_id),
+            upstream,
+            room_defaults,
+            rooms,
+            chords_channel_size: args
+                .chords_channel_size
+                .or(config.chords_channel_size)
+                .unwrap_or(64),
+            runtime: Runtime {
+                pingpong: args.pingpong.or(config.pingpong).unwrap_or(false),
+                heartbeat,
+                tcp_heartbeat,
+                listener_heartbeats,
+                drain_to,
+                shutdown_timeout,
+                handshake_timeout,
+                tls_timeout,
+            },
+        })
+    }
+
+    /// Read the configuration file, if any, and layer the arguments over it.
+    pub fn load(args: &Arguments) -> anyhow::Result<Self> {
+        let config = match &args.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        Self::resolve(args, config)
+    }
+
+    pub fn log(&self) {
+        if let Some(address) = self.address {
+            info!("Address: {address}");
+        }
+        if let Some(address) = self.tcp_address {
+            info!("Raw TCP address: {address}");
+        }
+        if let Some(path) = &self.unix_socket {
+            info!("Unix domain socket: {path:?}");
+        }
+        if let Some(path) = &self.lines_unix_socket {
+            info!("Raw Unix domain socket: {path:?}");
+        }
+        if let Some(address) = self.admin_address {
+            info!("Admin API address: {address}");
+        }
+        for listener in &self.listeners {
+            info!(
+                "Listener {}: TLS certificate {:?}, only {:?}",
+                listener.address,
+                listener.tls.as_ref().map(|tls| &tls.cert),
+                listener.role
+            );
+        }
+        if let Some(tls) = &self.tls {
+            info!("TLS certificate {:?} and key {:?}", tls.cert, tls.key);
+            if let Some(client_ca) = &tls.client_ca {
+                info!("Verifying client certificates against {client_ca:?}");
+            }
+            if tls.require_client_cert_for_publishers {
+                info!("Publishers need a verified client certificate");
+            }
+        }
+        if !self.auth.token_key_files.is_empty() {
+            info!(
+                "Verifying client tokens with {} key(s), anonymous consumers: {}",
+                self.auth.token_key_files.len(),
+                self.auth.anonymous_consumers
+            );
+        }
+        let admission = &self.admission;
+        if let Some(max) = admission.max_connections {
+            info!("Maximum connections: {max}");
+        }
+        if let Some(max) = admission.max_connections_per_ip {
+            info!("Maximum connections per address: {max}");
+        }
+        if !admission.allow.is_empty() {
+            info!("Allowing connections from {:?}", admission.allow);
+        }
+        if !admission.deny.is_empty() {
+            info!("Denying connections from {:?}", admission.deny);
+        }
+        if !self.origins.allowed.is_empty() {
+            info!("Allowing browser pages from {:?}", self.origins.allowed);
+        }
+        match &self.site.root {
+            Some(root) => info!("Serving {root:?} to browsers"),
+            None => info!("Serving the built-in page to browsers"),
+        }
+        if let Some(server_id) = &self.server_id {
+            info!("Server id: {server_id}");
+        }
+        if let Some(upstream) = &self.upstream {
+            info!(
+                "Relaying rooms {:?} from {}{}",
+                upstream.rooms,
+                upstream.address,
+                if upstream.secure { " over TLS" } else { "" }
+            );
+        }
+        if self.room_defaults.recognize_chords {
+            info!("Recognizing chords in pitch events");
+        }
+        if let Some(rate_limit) = &self.room_defaults.rate_limit {
+            info!("Publisher rate limit: {rate_limit:?}");
+        }
+        if let Some(directory) = &self.room_defaults.record {
+            info!("Recording sessions to {directory:?}");
+        }
+        for (name, room) in &self.rooms {
+            info!(
+                "Room \"{name}\": floor {:?}, ensemble: {}, recognizing chords: {}, rate limit: {:?}",
+                room.floor, room.ensemble, room.recognize_chords, room.rate_limit
+            );
+        }
+        info!("Consumer queue size: {}", self.chords_channel_size);
+        self.runtime.log_changes(None);
+    }
+
+    /// Warn about changed settings which only apply after a restart.
+    fn warn_restart_required(&self, new: &Self) {
+        let restart_required = [
+            ("address", self.address != new.address),
+            ("tcp_address", self.tcp_address != new.tcp_address),
+            ("unix_socket", self.unix_socket != new.unix_socket),
+            (
+                "lines_unix_socket",
+                self.lines_unix_socket != new.lines_unix_socket,
+            ),
+            ("admin_address", self.admin_address != new.admin_address),
+            ("tls", self.tls != new.tls),
+            ("listeners", self.listeners != new.listeners),
+            ("server_id", self.server_id != new.server_id),
+            ("upstream", self.upstream != new.upstream),
+            ("auth", self.auth != new.auth),
+            ("admission", self.admission != new.admission),
+            ("allowed_origins", self.origins != new.origins),
+            ("web_root", self.site != new.site),
+            (
+                "rooms",
+                self.room_defaults != new.room_defaults || self.rooms != new.rooms,
+            ),
+            (
+                "chords_channel_size",
+                self.chords_channel_size != new.chords_channel_size,
+            ),
+        ];
+        for (name, _) in restart_required.iter().filter(|(_, changed)| *changed) {
+            warn!("Changing {name} requires a restart, keeping the current value");
+        }
+    }
+}
+
+impl Runtime {
+    /// How to time websocket clients.
+    pub fn timing(&self) -> Timing {
+        Timing {
+            pingpong: self.pingpong,
+            heartbeat: self.heartbeat,
+            tls_timeout: self.tls_timeout,
+            handshake_timeout: self.handshake_timeout,
+        }
+    }
+
+    /// How to time raw TCP clients.
+    pub fn tcp_timing(&self) -> Timing {
+        Timing {
+            heartbeat: self.tcp_heartbeat,
+            ..self.timing()
+        }
+    }
+
+    /// Log the settings which differ from `previous`, or all of them.
+    fn log_changes(&self, previous: Option<&Self>) {
+        let all = previous.is_none();
+        let previous = previous.unwrap_or(self);
+        if all || self.pingpong != previous.pingpong {
+            info!("Applied pingpong: {}", self.pingpong);
+        }
+        if all || self.heartbeat != previous.heartbeat {
+            info!("Applied heartbeat: {:?}", self.heartbeat);
+        }
+        if all || self.tcp_heartbeat != previous.tcp_heartbeat {
+            info!("Applied raw TCP heartbeat: {:?}", self.tcp_heartbeat);
+        }
+        if all || self.listener_heartbeats != previous.listener_heartbeats {
+            for (address, heartbeat) in &self.listener_heartbeats {
+                info!("Applied heartbeat for listener {address}: {heartbeat:?}");
+            }
+        }
+        if all || self.drain_to != previous.drain_to {
+            info!("Applied drain address: {:?}", self.drain_to);
+        }
+        if all || self.shutdown_timeout != previous.shutdown_timeout {
+            info!("Applied shutdown timeout: {:?}", self.shutdown_timeout);
+        }
+        if all || self.handshake_timeout != previous.handshake_timeout {
+            info!("Applied handshake timeout: {:?}", self.handshake_timeout);
+        }
+        if all || self.tls_timeout != previous.tls_timeout {
+            info!("Applied TLS timeout: {:?}", self.tls_timeout);
+        }
+    }
+}
+
+/// Reload the configuration file on SIGHUP or when it changes, publishing the new runtime settings.
+pub async fn reload(
+    args: Arguments,
+    mut settings: Settings,
+    runtime: watch::Sender<Runtime>,
+) -> anyhow::Result<()> {
+    let path = args
+        .config
+        .clone()
+        .context("No configuration file to reload")?;
+    let mut hangup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+    let mut poll = tokio::time::interval(RELOAD_POLL_INTERVAL);
+    let mut last_modified = modified(&path);
+    loop {
+        let reload = select! {
+            _ = hangup.recv() => {
+                info!("Reloading configuration on SIGHUP");
+                true
+            }
+            _ = poll.tick() => {
+                let modified = modified(&path);
+                let changed = modified != last_modified;
+                last_modified = modified;
+                if changed {
+                    info!("Configuration file {path:?} changed, reloading");
+                }
+                changed
+            }
+        };
+        if !reload {
+            continue;
+        }
+        let new = match Settings::load(&args) {
+            Ok(new) => new,
+            Err(e) => {
+                warn!("Keeping the current configuration: {e:?}");
+                continue;
+            }
+        };
+        settings.warn_restart_required(&new);
+        new.runtime.log_changes(Some(&settings.runtime));
+        settings.runtime = new.runtime.clone();
+        runtime.send_if_modified(|current| {
+            let changed = *current != new.runtime;
+            *current = new.runtime;
+            changed
+        });
+    }
+}
+
+/// A duration from the arguments, else from the configuration file, else `default_secs`.
+fn duration(
+    arg: Option<humantime::Duration>,
+    config: Option<String>,
+    default_secs: u64,
+) -> anyhow::Result<Duration> {
+    match (arg, config) {
+        (Some(duration), _) => Ok(duration.into()),
+        (None, Some(text)) => Ok(humantime::parse_duration(&text)?),
+        (None, None) => Ok(Duration::from_secs(default_secs)),
+    }
+}
+
+/// When a file was last modified, if it can be read.
+pub(crate) fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::*;
+    use crate::rate_limit::RateLimitAction;
+
+    fn config(text: &str) -> Config {
+        toml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn layers_arguments_over_config() {
+        let args = Arguments::parse_from(["quinnipak", "--heartbeat", "20s/45s/15s"]);
+        let settings = Settings::resolve(
+            &args,
+            config(
+                r#"
+                address = "127.0.0.1:9000"
+                heartbeat = "10s/15s/5s"
+                pingpong = true
+                "#,
+            ),
+        )
+        .unwrap();
+        assert_eq!(settings.address, Some(([127, 0, 0, 1], 9000).into()));
+        assert_eq!(
+            settings.runtime.heartbeat,
+            parse_heartbeat("20s/45s/15s").unwrap()
+        );
+        assert_eq!(settings.runtime.tcp_heartbeat, settings.runtime.heartbeat);
+        assert!(settings.runtime.pingpong);
+        assert_eq!(settings.chords_channel_size, 64);
+    }
+
+    #[test]
+    fn negates_config_switches() {
+        let args = Arguments::parse_from([
+            "quinnipak",
+            "--pingpong=false",
+            "--anonymous-consumers=false",
+            "--recognize-chords",
+        ]);
+        let settings = Settings::resolve(
+            &args,
+            config(
+                r#"
+                pingpong = true
+                recognize_chords = false
+
+                [auth]
+                anonymous_consumers = true
+                "#,
+            ),
+        )
+        .unwrap();
+        assert!(!settings.runtime.pingpong);
+        assert!(!settings.auth.anonymous_consumers);
+        assert!(settings.room_defaults.recognize_chords);
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        assert!(toml::from_str::<Config>("adress = \"0.0.0.0:8000\"").is_err());
+        let args = Arguments::parse_from(["quinnipak"]);
+        assert!(Settings::resolve(&args, config(r#"heartbeat = "20s/15s/5s""#)).is_err());
+        assert!(Settings::resolve(&args, config(r#"tcp_heartbeat = "0s/15s/5s""#)).is_err());
+        let no_client_ca = r#"
+            [tls]
+            cert = "cert.pem"
+            key = "key.pem"
+            require_client_cert_for_publishers = true
+            "#;
+        assert!(Settings::resolve(&args, config(no_client_ca)).is_err());
+        let no_timeout = r#"
+            [rooms.choir]
+            floor = "exclusive"
+            floor_timeout = "soon"
+            "#;
+        assert!(Settings::resolve(&args, config(no_timeout)).is_err());
+    }
+
+    #[test]
+    fn resolves_listeners() {
+        let args = Arguments::parse_from(["quinnipak"]);
+        let settings = Settings::resolve(
+            &args,
+            config(
+                r#"
+                [[listeners]]
+                address = "192.168.1.2:8000"
+                heartbeat = "20s/45s/15s"
+
+                [[listeners]]
+                address = "0.0.0.0:443"
+                role = "consumer"
+                tls = { cert = "cert.pem", key = "key.pem" }
+                "#,
+            ),
+        )
+        .unwrap();
+        assert_eq!(settings.address, None);
+        let [lan, public] = &settings.listeners[..] else {
+            panic!("Expected two listeners, got {:?}", settings.listeners);
+        };
+        assert_eq!(
+            settings.runtime.listener_heartbeats.get(&lan.address),
+            Some(&parse_heartbeat("20s/45s/15s").unwrap())
+        );
+        assert!(!settings
+            .runtime
+            .listener_heartbeats
+            .contains_key(&public.address));
+        assert_eq!(lan.role, None);
+        assert!(lan.tls.is_none());
+        assert_eq!(public.role, Some(Role::Consumer));
+        assert_eq!(
+            public.tls.as_ref().map(|tls| tls.cert.clone()),
+            Some(PathBuf::from("cert.pem"))
+        );
+    }
+
+    #[test]
+    fn resolves_upstream() {
+        let args = Arguments::parse_from(["quinnipak", "--upstream-room", "jam"]);
+        let settings = Settings::resolve(
+            &args,
+            config(
+                r#"
+                [upstream]
+                address = "central.local:8000"
+                secure = true
+                rooms = ["rehearsal"]
+                "#,
+            ),
+        )
+        .unwrap();
+        let upstream = settings.upstream.unwrap();
+        assert_eq!(upstream.address, "central.local:8000");
+        assert!(upstream.secure);
+        assert_eq!(upstream.rooms, ["jam"]);
+
+        let args = Arguments::parse_from(["quinnipak", "--upstream", "central.local:8000"]);
+        let settings = Settings::resolve(&args, Config::default()).unwrap();
+        assert_eq!(settings.upstream.unwrap().rooms, [morivar::DEFAULT_ROOM]);
+        let settings = Settings::resolve(&Arguments::parse_from(["quinnipak"]), Config::default());
+        assert_eq!(settings.unwrap().upstream, None);
+    }
+
+    #[test]
+    fn resolves_admission() {
+        let args = Arguments::parse_from(["quinnipak", "--deny", "10.0.0.66/32"]);
+        let settings = Settings::resolve(
+            &args,
+            config(
+                r#"
+                max_connections_per_ip = 4
+                handshake_timeout = "3s"
+                allow = ["10.0.0.0/8"]
+                deny = ["10.0.0.1/32"]
+                allowed_origins = ["https://horeau.example"]
+                "#,
+            ),
+        )
+        .unwrap();
+        assert_eq!(settings.admission.max_connections, None);
+        assert_eq!(settings.admission.max_connections_per_ip, Some(4));
+        assert_eq!(settings.admission.allow, ["10.0.0.0/8".parse().unwrap()]);
+        assert_eq!(settings.admission.deny, ["10.0.0.66/32".parse().unwrap()]);
+        assert_eq!(settings.origins.allowed, ["https://horeau.example"]);
+        assert_eq!(settings.runtime.handshake_timeout, Duration::from_secs(3));
+        assert_eq!(settings.runtime.tls_timeout, Duration::from_secs(10));
+        assert!(toml::from_str::<Config>(r#"allow = ["10.0.0.0/33"]"#).is_err());
+    }
+
+    #[test]
+    fn resolves_room_settings() {
+        let args = Arguments::parse_from(["quinnipak"]);
+        let settings = Settings::resolve(
+            &args,
+            config(
+                r#"
+                recognize_chords = true
+
+                [rooms.choir]
+                floor = "priority"
+                floor_timeout = "10s"
+                priority = ["conductor", "soloist"]
+
+                [rooms.jam]
+                ensemble = true
+                recognize_chords = false
+                "#,
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            settings.rooms["choir"].floor,
+            FloorPolicy::Priority {
+                order: vec!["conductor".to_string(), "soloist".to_string()],
+                timeout: Duration::from_secs(10),
+            }
+        );
+        assert!(!settings.rooms["choir"].ensemble);
+        assert_eq!(settings.rooms["jam"].floor, FloorPolicy::FreeForAll);
+        assert!(settings.rooms["jam"].ensemble);
+        assert!(settings.room_defaults.recognize_chords);
+        assert!(settings.rooms["choir"].recognize_chords);
+        assert!(!settings.rooms["jam"].recognize_chords);
+    }
+
+    #[test]
+    fn layers_rate_limits() {
+        let limits = r#"
+            [rate_limit]
+            events_per_second = 20
+
+            [rooms.jam.rate_limit]
+            events_per_second = 5
+            action = "coalesce"
+            "#;
+        let args = Arguments::parse_from(["quinnipak"]);
+        let settings = Settings::resolve(&args, config(limits)).unwrap();
+        assert_eq!(
+            settings.room_defaults.rate_limit,
+            Some(RateLimit {
+                events_per_second: 20,
+                burst: None,
+                action: RateLimitAction::Drop,
+            })
+        );
+        assert_eq!(
+            settings.rooms["jam"].rate_limit.map(|limit| limit.action),
+            Some(RateLimitAction::Coalesce)
+        );
+
+        let args = Arguments::parse_from(["quinnipak", "--rate-limit", "50"]);
+        let settings = Settings::resolve(&args, config(limits)).unwrap();
+        assert_eq!(
+            settings
+                .room_defaults
+                .rate_limit
+                .map(|limit| limit.events_per_second),
+            Some(50)
+        );
+
+        let args = Arguments::parse_from(["quinnipak"]);
+        let no_rate = "rate_limit = { events_per_second = 0 }";
+        assert!(Settings::resolve(&args, config(no_rate)).is_err());
+    }
+}
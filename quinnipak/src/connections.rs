@@ -0,0 +1,245 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+
+use crate::{metrics::METRICS, transport::CloseReason};
+
+/// What a client identified as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    #[serde(alias = "publisher")]
+    Publisher,
+    #[serde(alias = "consumer")]
+    Consumer,
+}
+
+impl Role {
+    /// The label for this role on metrics.
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Publisher => "publisher",
+            Self::Consumer => "consumer",
+        }
+    }
+}
+
+/// A connected client, as listed on the admin API.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConnectionInfo {
+    /// Unique per server run, unlike the client-chosen id
+    pub connection: u64,
+    pub role: Role,
+    pub id: String,
+    pub room: String,
+    pub peer: Option<SocketAddr>,
+    /// The last measured round trip time in milliseconds, if the transport measures it
+    pub rtt_ms: Option<f64>,
+}
+
+/// Why a connection is asked to leave.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Leave {
+    Kicked,
+    ShutDown,
+    /// The server drains, clients should reconnect to this address
+    Redirect(String),
+}
+
+impl Leave {
+    pub fn close_reason(&self) -> CloseReason {
+        match self {
+            Self::Kicked => CloseReason::Normal,
+            Self::ShutDown | Self::Redirect(_) => CloseReason::GoingAway,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    info: ConnectionInfo,
+    kick: CancellationToken,
+}
+
+/// All identified clients of this server.
+#[derive(Debug, Clone, Default)]
+pub struct Connections {
+    entries: Arc<Mutex<HashMap<u64, Entry>>>,
+    next: Arc<AtomicU64>,
+    shutdown: CancellationToken,
+    redirect: Arc<OnceLock<String>>,
+}
+
+impl Connections {
+    /// List a client until the returned registration is dropped.
+    pub fn register(
+        &self,
+        role: Role,
+        id: &str,
+        room: &str,
+        peer: Option<SocketAddr>,
+    ) -> Registration {
+        let connection = self.next.fetch_add(1, Ordering::Relaxed);
+        METRICS
+            .connected
+            .with_label_values(&[room, role.label()])
+            .inc();
+        // Shutting down kicks every connection, including ones registered afterwards.
+        let kick = self.shutdown.child_token();
+        let info = ConnectionInfo {
+            connection,
+            role,
+            id: id.to_string(),
+            room: room.to_string(),
+            peer,
+            rtt_ms: None,
+        };
+        self.entries.lock().unwrap().insert(
+            connection,
+            Entry {
+                info,
+                kick: kick.clone(),
+            },
+        );
+        Registration {
+            connection,
+            connections: self.clone(),
+            kick,
+        }
+    }
+
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut list = self
+            .entries
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect::<Vec<_>>();
+        list.sort_by_key(|info| info.connection);
+        list
+    }
+
+    /// Ask a connection to close. Returns `false` if there is no such connection.
+    pub fn kick(&self, connection: u64) -> bool {
+        let entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get(&connection) else {
+            return false;
+        };
+        entry.kick.cancel();
+        true
+    }
+
+    /// Ask all connections to leave, optionally redirecting them to another server.
+    pub fn shut_down(&self, redirect: Option<String>) {
+        if let Some(address) = redirect {
+            let _ = self.redirect.set(address);
+        }
+        self.shutdown.cancel();
+    }
+
+    /// Completes once the server shuts down.
+    pub fn shutting_down(&self) -> WaitForCancellationFuture<'_> {
+        self.shutdown.cancelled()
+    }
+}
+
+/// Keeps a client listed in [`Connections`] while it is connected.
+#[derive(Debug)]
+pub struct Registration {
+    connection: u64,
+    connections: Connections,
+    kick: CancellationToken,
+}
+
+impl Registration {
+    /// The connection number, as listed by the admin API.
+    pub fn connection(&self) -> u64 {
+        self.connection
+    }
+
+    pub fn set_rtt(&self, rtt: Option<Duration>) {
+        if let Some(entry) = self
+            .connections
+            .entries
+            .lock()
+            .unwrap()
+            .get_mut(&self.connection)
+        {
+            entry.info.rtt_ms = rtt.map(|rtt| rtt.as_secs_f64() * 1000.0);
+        }
+    }
+
+    /// Completes once the connection should leave, because it was kicked or the server shuts down.
+    pub async fn leave(&self) -> Leave {
+        self.kick.cancelled().await;
+        if !self.connections.shutdown.is_cancelled() {
+            return Leave::Kicked;
+        }
+        match self.connections.redirect.get() {
+            Some(address) => Leave::Redirect(address.clone()),
+            None => Leave::ShutDown,
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let entry = self
+            .connections
+            .entries
+            .lock()
+            .unwrap()
+            .remove(&self.connection);
+        if let Some(Entry { info, .. }) = entry {
+            METRICS
+                .connected
+                .with_label_values(&[&info.room, info.role.label()])
+                .dec();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lists_until_dropped() {
+        let connections = Connections::default();
+        let first = connections.register(Role::Publisher, "pekisch", "default", None);
+        let second = connections.register(Role::Consumer, "abegg", "default", None);
+        assert_eq!(connections.list().len(), 2);
+        drop(first);
+        let list = connections.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].id, "abegg");
+        assert!(connections.kick(list[0].connection));
+        drop(second);
+        assert!(connections.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn redirects_on_shutdown() {
+        let connections = Connections::default();
+        let registration = connections.register(Role::Consumer, "abegg", "default", None);
+        connections.shut_down(Some("backup:8000".to_string()));
+        assert_eq!(
+            registration.leave().await,
+            Leave::Redirect("backup:8000".to_string())
+        );
+        let late = connections.register(Role::Consumer, "pehnt", "default", None);
+        assert_eq!(
+            late.leave().await,
+            Leave::Redirect("backup:8000".to_string())
+        );
+    }
+}
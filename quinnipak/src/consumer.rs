@@ -1,38 +1,43 @@
 use anyhow::Context;
-use futures_util::SinkExt;
-use morivar::{ConsumerToServer, ServerToConsumer, ToMessage};
+use morivar::{ConsumerToServer, Heartbeat, ServerToConsumer};
 use simple_tokio_watchdog::{Expired, Signal, Watchdog};
-use tokio::{
-    io::{AsyncRead, AsyncWrite},
-    sync::broadcast,
+use tracing::{info, warn};
+
+use crate::{
+    connections::{Leave, Registration},
+    metrics::METRICS,
+    room::Subscription,
+    transport::Transport,
 };
-use tokio_websockets::{Message, WebsocketStream};
-use tracing::info;
 
-pub async fn run<S>(
-    mut chords_receiver: broadcast::Receiver<ServerToConsumer>,
-    mut stream: WebsocketStream<S>,
+pub async fn run<T>(
+    subscription: Subscription,
+    mut transport: T,
+    registration: Registration,
     pingpong: bool,
+    heartbeat: Heartbeat,
 ) -> anyhow::Result<()>
 where
-    S: AsyncRead + AsyncWrite + Unpin,
+    T: Transport,
 {
-    let (watchdog, mut expired) = Watchdog::with_timeout(morivar::PING_AWAIT_INTERVAL).run();
+    let (watchdog, mut expired) = Watchdog::with_timeout(heartbeat.ping_await_interval).run();
+    let mut probes = tokio::time::interval(heartbeat.ping_interval);
     loop {
         tokio::select! {
-            event = chords_receiver.recv() => {
-                let m = event.context("Failed to receive message on internal chord broadcast")?;
-                stream.send(m.to_message()).await?;
+            event = subscription.recv() => {
+                transport.send(event.message).await?;
+                METRICS.publish_to_send.observe(event.published.elapsed().as_secs_f64());
             }
-            item = stream.next() => {
+            item = transport.receive::<ConsumerToServer>() => {
                 match item {
-                    Some(Ok(ref msg)) => {
-                        let response = handle_consumer_message(msg)?;
+                    Some(Ok(msg)) => {
+                        let response = handle_consumer_message(msg);
                         watchdog.send(Signal::Reset).await?;
-                        stream.send(response.to_message()).await?;
+                        transport.send(response).await?;
                     }
-                    Some(e) => {
-                        e.context("Error on websocket client connection")?;
+                    Some(Err(e)) => {
+                        // TODO limit message length perhaps.
+                        return Err(e).context("Expected ConsumerToServer::Ping");
                     }
                     None => {
                         info!("Consumer stream closed!");
@@ -40,8 +45,23 @@ where
                     }
                 }
             }
+            _p = probes.tick() => {
+                registration.set_rtt(transport.rtt());
+                transport.probe().await?;
+            }
+            leave = registration.leave() => {
+                info!("Consumer leaving: {leave:?}");
+                // Nothing should keep sounding on a consumer which is cut off.
+                transport.send(ServerToConsumer::Silence).await?;
+                if let Leave::Redirect(address) = &leave {
+                    let address = address.clone();
+                    transport.send(ServerToConsumer::Redirect { address }).await?;
+                }
+                return transport.close(leave.close_reason()).await;
+            }
             e = &mut expired, if pingpong => {
                 let Expired = e.context("Failed to monitor watchdog")?;
+                METRICS.watchdog_expirations.with_label_values(&["consumer"]).inc();
                 anyhow::bail!("Consumer failed to ping");
             }
         }
@@ -49,15 +69,11 @@ where
     Ok(())
 }
 
-fn handle_consumer_message(msg: &Message) -> anyhow::Result<ServerToConsumer> {
-    if matches!(
-        msg.as_text().map(serde_json::from_str),
-        Ok(Ok(ConsumerToServer::Ping))
-    ) {
-        info!("Sending Pong");
-        Ok(ServerToConsumer::Pong)
-    } else {
-        // TODO limit message length perhaps.
-        anyhow::bail!("Expected ConsumerToServer::Ping, got: {msg:?}");
+fn handle_consumer_message(msg: ConsumerToServer) -> ServerToConsumer {
+    match msg {
+        ConsumerToServer::Ping => {
+            info!("Sending Pong");
+            ServerToConsumer::Pong
+        }
     }
 }
@@ -0,0 +1,231 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use klib::core::{chord::HasChord, note::Note};
+use morivar::{ServerToConsumer, SoundingNotes, TunedNote};
+use tracing::warn;
+
+use crate::recognition::recognize;
+
+/// Merges the sounding notes of all publishers in a room, so several players can build one chord.
+#[derive(Debug, Default)]
+pub struct Ensemble {
+    /// The notes each publisher's connection currently sounds
+    parts: Mutex<HashMap<u64, Vec<TunedNote>>>,
+}
+
+impl Ensemble {
+    /// Join the ensemble as the publisher with the given connection, leaving it when dropped.
+    pub fn part(self: &Arc<Self>, connection: u64) -> Part {
+        Part {
+            ensemble: Arc::clone(self),
+            connection,
+            sounding: SoundingNotes::default(),
+        }
+    }
+
+    /// Replace the notes of one part, returning the event for the union of all parts.
+    fn play(&self, connection: u64, notes: Vec<TunedNote>) -> ServerToConsumer {
+        let mut parts = self.parts.lock().unwrap();
+        if notes.is_empty() {
+            parts.remove(&connection);
+        } else {
+            parts.insert(connection, notes);
+        }
+        merged(&parts)
+    }
+
+    /// Remove the part of `connection`, returning the event for the remaining parts if it sounded anything.
+    fn leave(&self, connection: u64) -> Option<ServerToConsumer> {
+        let mut parts = self.parts.lock().unwrap();
+        parts.remove(&connection).map(|_| merged(&parts))
+    }
+}
+
+/// The event for all `parts` sounding together: the recognized chord, the bare pitches, or silence.
+///
+/// Detuned notes are kept as tuned pitches, since a chord or bare pitches would lose their cents.
+fn merged(parts: &HashMap<u64, Vec<TunedNote>>) -> ServerToConsumer {
+    let mut tuned: Vec<TunedNote> = Vec::new();
+    for note in parts.values().flatten() {
+        if !tuned.contains(note) {
+            tuned.push(*note);
+        }
+    }
+    if tuned.is_empty() {
+        return ServerToConsumer::Silence;
+    }
+    if tuned.iter().any(|note| note.cents != 0.0) {
+        return ServerToConsumer::TunedPitchesEvent(tuned);
+    }
+    let notes: HashSet<Note> = tuned.into_iter().map(|tuned| tuned.note).collect();
+    match recognize(&notes).into_iter().next() {
+        Some(chord) => ServerToConsumer::ChordEvent(chord),
+        None => ServerToConsumer::PitchesEvent(notes),
+    }
+}
+
+/// One publisher's contribution to an [`Ensemble`].
+#[derive(Debug)]
+pub struct Part {
+    ensemble: Arc<Ensemble>,
+    connection: u64,
+    sounding: SoundingNotes,
+}
+
+impl Part {
+    /// Apply an event of this publisher, returning the event to broadcast for the whole ensemble.
+    pub fn merge(&mut self, message: ServerToConsumer) -> ServerToConsumer {
+        let notes: HashSet<Note> = match message {
+            ServerToConsumer::ChordEvent(chord) => chord.chord().into_iter().collect(),
+            ServerToConsumer::PitchesEvent(pitches) => pitches,
+            ServerToConsumer::TunedPitchesEvent(pitches) => {
+                return self.ensemble.play(self.connection, pitches);
+            }
+            ServerToConsumer::NotesOn(delta) => {
+                if !self.sounding.notes_on(&delta) {
+                    warn!("Publisher skipped a notes delta, merging possibly wrong notes");
+                }
+                self.sounding.notes().clone()
+            }
+            ServerToConsumer::NotesOff(delta) => {
+                if !self.sounding.notes_off(&delta) {
+                    warn!("Publisher skipped a notes delta, merging possibly wrong notes");
+                }
+                self.sounding.notes().clone()
+            }
+            ServerToConsumer::Keyframe(keyframe) => {
+                self.sounding.keyframe(&keyframe);
+                self.sounding.notes().clone()
+            }
+            ServerToConsumer::Silence => {
+                self.sounding.clear();
+                HashSet::new()
+            }
+            other => return other,
+        };
+        let notes = notes.into_iter().map(TunedNote::from).collect();
+        self.ensemble.play(self.connection, notes)
+    }
+
+    /// Silence the part of another publisher, e.g. one this publisher took the floor from.
+    ///
+    /// Returns the event for the remaining parts.
+    pub fn displace(&self, connection: u64) -> ServerToConsumer {
+        self.ensemble.play(connection, Vec::new())
+    }
+
+    /// Leave the ensemble, returning the event for the remaining parts if this part sounded anything.
+    pub fn leave(self) -> Option<ServerToConsumer> {
+        self.ensemble.leave(self.connection)
+    }
+}
+
+impl Drop for Part {
+    fn drop(&mut self) {
+        self.ensemble.leave(self.connection);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use klib::core::{named_pitch::NamedPitch, octave::Octave};
+
+    use super::*;
+
+    fn note(pitch: NamedPitch) -> Note {
+        Note::new(pitch, Octave::Four)
+    }
+
+    fn pitches(notes: &[NamedPitch]) -> ServerToConsumer {
+        ServerToConsumer::PitchesEvent(notes.iter().copied().map(note).collect())
+    }
+
+    #[test]
+    fn merges_parts_into_chord() {
+        let ensemble = Arc::new(Ensemble::default());
+        let mut bass = ensemble.part(1);
+        let mut treble = ensemble.part(2);
+        assert!(matches!(
+            bass.merge(pitches(&[NamedPitch::C])),
+            ServerToConsumer::PitchesEvent(_)
+        ));
+        let ServerToConsumer::ChordEvent(chord) =
+            treble.merge(pitches(&[NamedPitch::E, NamedPitch::G]))
+        else {
+            panic!("Expected a chord");
+        };
+        assert_eq!(chord.chord().len(), 3);
+    }
+
+    #[test]
+    fn silence_removes_own_part() {
+        let ensemble = Arc::new(Ensemble::default());
+        let mut bass = ensemble.part(1);
+        let mut treble = ensemble.part(2);
+        bass.merge(pitches(&[NamedPitch::C, NamedPitch::E]));
+        treble.merge(pitches(&[NamedPitch::G]));
+        let ServerToConsumer::PitchesEvent(remaining) = bass.merge(ServerToConsumer::Silence)
+        else {
+            panic!("Expected pitches");
+        };
+        assert_eq!(remaining, HashSet::from([note(NamedPitch::G)]));
+        drop(treble);
+        assert!(matches!(
+            bass.merge(ServerToConsumer::Silence),
+            ServerToConsumer::Silence
+        ));
+    }
+
+    #[test]
+    fn leaving_silences_own_part() {
+        let ensemble = Arc::new(Ensemble::default());
+        let mut bass = ensemble.part(1);
+        let mut treble = ensemble.part(2);
+        let idle = ensemble.part(3);
+        bass.merge(pitches(&[NamedPitch::C]));
+        treble.merge(pitches(&[NamedPitch::G]));
+        assert!(idle.leave().is_none());
+        let Some(ServerToConsumer::PitchesEvent(remaining)) = treble.leave() else {
+            panic!("Expected pitches");
+        };
+        assert_eq!(remaining, HashSet::from([note(NamedPitch::C)]));
+        assert!(matches!(bass.leave(), Some(ServerToConsumer::Silence)));
+    }
+
+    #[test]
+    fn displacing_silences_only_that_part() {
+        let ensemble = Arc::new(Ensemble::default());
+        let mut bass = ensemble.part(1);
+        let mut treble = ensemble.part(2);
+        let soloist = ensemble.part(3);
+        bass.merge(pitches(&[NamedPitch::C]));
+        treble.merge(pitches(&[NamedPitch::G]));
+        let ServerToConsumer::PitchesEvent(remaining) = soloist.displace(2) else {
+            panic!("Expected pitches");
+        };
+        assert_eq!(remaining, HashSet::from([note(NamedPitch::C)]));
+    }
+
+    #[test]
+    fn keeps_cents_of_tuned_parts() {
+        let ensemble = Arc::new(Ensemble::default());
+        let mut bass = ensemble.part(1);
+        let mut treble = ensemble.part(2);
+        bass.merge(pitches(&[NamedPitch::C]));
+        let detuned = TunedNote {
+            note: note(NamedPitch::B),
+            cents: -31.0,
+        };
+        let ServerToConsumer::TunedPitchesEvent(merged) =
+            treble.merge(ServerToConsumer::TunedPitchesEvent(vec![detuned]))
+        else {
+            panic!("Expected tuned pitches");
+        };
+        assert_eq!(merged.len(), 2);
+        assert!(merged.contains(&detuned));
+        assert!(merged.contains(&TunedNote::from(note(NamedPitch::C))));
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::watch;
+
+/// Which publisher may publish in a room.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum FloorPolicy {
+    /// Every publisher publishes, interleaved
+    #[default]
+    FreeForAll,
+    /// The first publisher holds the floor until it publishes silence, leaves or stays quiet for `timeout`
+    Exclusive { timeout: Duration },
+    /// Like [`Self::Exclusive`], but publishers earlier in `order` take the floor from later or unlisted ones
+    Priority {
+        order: Vec<String>,
+        timeout: Duration,
+    },
+}
+
+/// Whether a publisher may publish an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Granted,
+    /// Granted, taking the floor from the publisher with the given connection, whose notes may still sound
+    TookOver(u64),
+    Denied,
+}
+
+#[derive(Debug)]
+struct Holder {
+    connection: u64,
+    rank: usize,
+    last_active: Instant,
+}
+
+/// The floor of a room, held by at most one publisher unless the policy is [`FloorPolicy::FreeForAll`].
+#[derive(Debug)]
+pub struct Floor {
+    policy: FloorPolicy,
+    holder: Mutex<Option<Holder>>,
+    changes: watch::Sender<Option<u64>>,
+}
+
+impl Floor {
+    pub fn new(policy: FloorPolicy) -> Self {
+        Self {
+            policy,
+            holder: Mutex::default(),
+            changes: watch::Sender::new(None),
+        }
+    }
+
+    /// Take a seat for the publisher with the given connection and id, giving up the floor when dropped.
+    pub fn seat(self: &Arc<Self>, connection: u64, id: &str) -> Seat {
+        let rank = match &self.policy {
+            FloorPolicy::Priority { order, .. } => order
+                .iter()
+                .position(|ranked| ranked == id)
+                .unwrap_or(order.len()),
+            _ => 0,
+        };
+        Seat {
+            floor: Arc::clone(self),
+            connection,
+            rank,
+            changes: self.changes.subscribe(),
+            holding: false,
+        }
+    }
+
+    fn request(&self, connection: u64, rank: usize) -> Access {
+        let timeout = match &self.policy {
+            FloorPolicy::FreeForAll => return Access::Granted,
+            FloorPolicy::Exclusive { timeout } | FloorPolicy::Priority { timeout, .. } => *timeout,
+        };
+        let now = Instant::now();
+        let mut holder = self.holder.lock().unwrap();
+        let access = match holder.as_mut() {
+            Some(current) if current.connection == connection => {
+                current.last_active = now;
+                return Access::Granted;
+            }
+            Some(current) if rank < current.rank || now - current.last_active >= timeout => {
+                Access::TookOver(current.connection)
+            }
+            Some(_) => return Access::Denied,
+            None => Access::Granted,
+        };
+        *holder = Some(Holder {
+            connection,
+            rank,
+            last_active: now,
+        });
+        self.changes.send_replace(Some(connection));
+        access
+    }
+
+    fn release(&self, connection: u64) {
+        let mut holder = self.holder.lock().unwrap();
+        if holder
+            .as_ref()
+            .is_some_and(|current| current.connection == connection)
+        {
+            *holder = None;
+            self.changes.send_replace(None);
+        }
+    }
+}
+
+/// A publisher's place at a room's floor.
+#[derive(Debug)]
+pub struct Seat {
+    floor: Arc<Floor>,
+    connection: u64,
+    rank: usize,
+    changes: watch::Receiver<Option<u64>>,
+    holding: bool,
+}
+
+impl Seat {
+    /// Ask to publish an event, taking the floor if the policy allows.
+    pub fn request(&self) -> Access {
+        self.floor.request(self.connection, self.rank)
+    }
+
+    /// Give up the floor, e.g. after publishing silence.
+    pub fn release(&self) {
+        self.floor.release(self.connection);
+    }
+
+    /// Whether this publisher may publish, without taking the floor.
+    pub fn holds(&self) -> bool {
+        self.floor.policy == FloorPolicy::FreeForAll
+            || *self.changes.borrow() == Some(self.connection)
+    }
+
+    /// Completes when this publisher gained (`true`) or lost (`false`) the floor.
+    pub async fn changed(&mut self) -> bool {
+        loop {
+            if self.changes.changed().await.is_err() {
+                // The floor outlives its seats, so this does not happen.
+                std::future::pending::<()>().await;
+            }
+            let holding = *self.changes.borrow_and_update() == Some(self.connection);
+            if holding != self.holding {
+                self.holding = holding;
+                return holding;
+            }
+        }
+    }
+}
+
+impl Drop for Seat {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exclusive_floor_until_release() {
+        let floor = Arc::new(Floor::new(FloorPolicy::Exclusive {
+            timeout: Duration::from_secs(60),
+        }));
+        let first = floor.seat(1, "jobbard");
+        let second = floor.seat(2, "pekisch");
+        assert_eq!(first.request(), Access::Granted);
+        assert_eq!(second.request(), Access::Denied);
+        first.release();
+        assert_eq!(second.request(), Access::Granted);
+        drop(second);
+        assert_eq!(first.request(), Access::Granted);
+    }
+
+    #[test]
+    fn exclusive_floor_times_out() {
+        let floor = Arc::new(Floor::new(FloorPolicy::Exclusive {
+            timeout: Duration::ZERO,
+        }));
+        let first = floor.seat(1, "jobbard");
+        let second = floor.seat(2, "pekisch");
+        assert_eq!(first.request(), Access::Granted);
+        assert_eq!(second.request(), Access::TookOver(1));
+        assert!(second.holds());
+        assert!(!first.holds());
+    }
+
+    #[test]
+    fn priority_takes_floor() {
+        let floor = Arc::new(Floor::new(FloorPolicy::Priority {
+            order: vec!["pekisch".to_string(), "jobbard".to_string()],
+            timeout: Duration::from_secs(60),
+        }));
+        let stranger = floor.seat(1, "netcat");
+        let jobbard = floor.seat(2, "jobbard");
+        let pekisch = floor.seat(3, "pekisch");
+        assert_eq!(stranger.request(), Access::Granted);
+        assert_eq!(jobbard.request(), Access::TookOver(1));
+        assert_eq!(stranger.request(), Access::Denied);
+        assert_eq!(pekisch.request(), Access::TookOver(2));
+        assert_eq!(jobbard.request(), Access::Denied);
+    }
+
+    #[tokio::test]
+    async fn notifies_seats() {
+        let floor = Arc::new(Floor::new(FloorPolicy::Priority {
+            order: vec!["pekisch".to_string()],
+            timeout: Duration::from_secs(60),
+        }));
+        let mut jobbard = floor.seat(1, "jobbard");
+        let pekisch = floor.seat(2, "pekisch");
+        jobbard.request();
+        assert!(jobbard.changed().await);
+        pekisch.request();
+        assert!(!jobbard.changed().await);
+    }
+}
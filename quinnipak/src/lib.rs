@@ -1,97 +1,333 @@
 #![doc = include_str!("../README.md")]
 
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
 use anyhow::Context;
+use auth::{AuthPolicy, Authentication};
+use connections::{Connections, Role};
+use metrics::METRICS;
 use morivar::ClientToServer;
-use morivar::ServerToConsumer;
+use morivar::Heartbeat;
+use morivar::ServerToClient;
 use morivar::PROTOCOL_VERSION;
+use origin::{OriginError, Origins, Rewind};
+use room::Rooms;
+use secure::Security;
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
-use tokio::sync::broadcast;
-use tokio_rustls::TlsAcceptor;
-use tokio_websockets::Message;
-use tokio_websockets::ServerBuilder;
-use tokio_websockets::WebsocketStream;
+use tokio_websockets::{ServerBuilder, WebsocketStream};
 use tracing::info;
+use transport::{CloseReason, Transport, WebsocketTransport};
+use web::Site;
 
+pub mod admin;
+pub mod admission;
+pub mod auth;
 pub mod cli;
+pub mod config;
+pub mod connections;
 mod consumer;
+pub mod ensemble;
+pub mod floor;
+pub mod line;
+pub mod listener;
+pub mod metrics;
+pub mod origin;
+mod outbox;
 mod publisher;
+pub mod rate_limit;
+pub mod recognition;
+pub mod recorder;
+pub mod relay;
+pub mod replay;
+pub mod room;
 pub mod secure;
+pub mod systemd;
+pub mod transport;
+pub mod web;
+
+/// How quinnipak times its clients.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timing {
+    /// Whether to cut off clients which stop pinging
+    pub pingpong: bool,
+    pub heartbeat: Heartbeat,
+    /// How long the TLS handshake may take
+    pub tls_timeout: Duration,
+    /// How long the websocket upgrade, and then identifying, may take
+    pub handshake_timeout: Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            pingpong: false,
+            heartbeat: Heartbeat::default(),
+            tls_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+}
 
-/// Handle the connection
+/// Handle the connection, a websocket client or a browser asking for the web client
 pub async fn quinnipak<Stream>(
     stream: Stream,
-    chords_tx: broadcast::Sender<ServerToConsumer>,
-    acceptor: Option<TlsAcceptor>,
-    pingpong: bool,
+    peer: Option<SocketAddr>,
+    rooms: Rooms,
+    connections: Connections,
+    security: Security,
+    site: Site,
+    timing: Timing,
 ) -> anyhow::Result<()>
 where
-    Stream: AsyncRead + AsyncWrite + Unpin,
+    Stream: AsyncRead + AsyncWrite + Unpin + Send,
 {
-    if let Some(acceptor) = acceptor {
+    if let Some(tls) = security.tls {
         info!("Accepting encrypted connection");
-        let stream = acceptor.accept(stream).await?;
-        // The type of `wss` is `WebsocketStream<TlsStream<TcpStream>>`
-        let wss = ServerBuilder::new()
-            .accept(stream)
-            .await
-            .context("Failed to accept secured websocket client")?;
-        handle_client(wss, chords_tx, pingpong).await?;
+        let (stream, certificate_subject) =
+            within(timing.tls_timeout, "tls_timeout", tls.accept(stream))
+                .await?
+                .inspect_err(|_| handshake_failed("tls"))?;
+        // The type of `wss` is `WebsocketStream<Rewind<TlsStream<TcpStream>>>`
+        let Some(wss) = upgrade(stream, &security.origins, &site, timing).await? else {
+            return Ok(());
+        };
+        let transport = WebsocketTransport::new(wss);
+        let authentication = Authentication {
+            certificate_subject,
+            policy: security.auth,
+        };
+        handle_client(transport, peer, authentication, rooms, connections, timing).await?;
     } else {
         info!("Accepting connection");
-        // The type of `ws` is `WebsocketStream<TcpStream>`
-        let ws = ServerBuilder::new()
-            .accept(stream)
-            .await
-            .context("Failed to accept websocket client")?;
-        handle_client(ws, chords_tx, pingpong).await?;
+        // The type of `ws` is `WebsocketStream<Rewind<TcpStream>>`
+        let Some(ws) = upgrade(stream, &security.origins, &site, timing).await? else {
+            return Ok(());
+        };
+        let transport = WebsocketTransport::new(ws);
+        let authentication = Authentication {
+            certificate_subject: None,
+            policy: security.auth,
+        };
+        handle_client(transport, peer, authentication, rooms, connections, timing).await?;
     }
     anyhow::Ok(())
 }
 
+/// Accept a websocket upgrade from an allowed origin, or serve `site` to a plain HTTP request.
+async fn upgrade<Stream>(
+    stream: Stream,
+    origins: &Origins,
+    site: &Site,
+    timing: Timing,
+) -> anyhow::Result<Option<WebsocketStream<Rewind<Stream>>>>
+where
+    Stream: AsyncRead + AsyncWrite + Unpin,
+{
+    // Reading the request and accepting the upgrade share the handshake timeout.
+    let deadline = Instant::now() + timing.handshake_timeout;
+    let (request, mut stream) = within(timing.handshake_timeout, "timeout", origins.check(stream))
+        .await?
+        .inspect_err(|e| match e {
+            OriginError::NotAllowed(_) => handshake_failed("origin"),
+            OriginError::Request(_) => handshake_failed("websocket"),
+        })?;
+    if !request.upgrade {
+        site.serve(&request, &mut stream)
+            .await
+            .context("Failed to serve the web client")?;
+        return Ok(None);
+    }
+    let ws = within(
+        deadline.saturating_duration_since(Instant::now()),
+        "timeout",
+        ServerBuilder::new().accept(stream),
+    )
+    .await?
+    .inspect_err(|_| handshake_failed("websocket"))
+    .context("Failed to accept websocket client")?;
+    Ok(Some(ws))
+}
+
+/// Handle a line-based connection, speaking either newline-delimited JSON or text commands
+pub async fn quinnipak_lines<Stream>(
+    stream: Stream,
+    peer: Option<SocketAddr>,
+    rooms: Rooms,
+    connections: Connections,
+    auth: AuthPolicy,
+    timing: Timing,
+) -> anyhow::Result<()>
+where
+    Stream: AsyncRead + AsyncWrite + Send,
+{
+    info!("Accepting line-based connection");
+    let transport = line::LineTransport::new(stream);
+    let authentication = Authentication {
+        certificate_subject: None,
+        policy: auth,
+    };
+    handle_client(transport, peer, authentication, rooms, connections, timing).await
+}
+
 pub async fn handle_client<T>(
-    mut stream: WebsocketStream<T>,
-    chords_sender: broadcast::Sender<ServerToConsumer>,
-    pingpong: bool,
+    mut transport: T,
+    peer: Option<SocketAddr>,
+    authentication: Authentication,
+    rooms: Rooms,
+    connections: Connections,
+    timing: Timing,
 ) -> anyhow::Result<()>
 where
-    T: AsyncRead + AsyncWrite + Unpin,
+    T: Transport,
+{
+    let Timing {
+        pingpong,
+        heartbeat,
+        handshake_timeout,
+        ..
+    } = timing;
+    let (role, id, room, relayed_to) = within(
+        handshake_timeout,
+        "timeout",
+        handshake(&mut transport, &authentication, heartbeat),
+    )
+    .await??;
+    let room = rooms.join(Some(&room));
+    match role {
+        Role::Publisher => {
+            info!("Identified \"{id}\" as publisher in room \"{}\"", room.name);
+            let registration = connections.register(Role::Publisher, &id, &room.name, peer);
+            let seat = room.floor.seat(registration.connection(), &id);
+            let performer = room.recorder.as_ref().map(|recorder| recorder.join(&id));
+            publisher::run(
+                room,
+                transport,
+                registration,
+                seat,
+                performer,
+                pingpong,
+                heartbeat,
+            )
+            .await?;
+        }
+        Role::Consumer => {
+            info!("Identified \"{id}\" as consumer in room \"{}\"", room.name);
+            if relayed_to.iter().any(|server| server == rooms.server_id()) {
+                handshake_failed("relay_loop");
+                let _ = transport.close(CloseReason::PolicyViolation).await;
+                anyhow::bail!(
+                    "Relaying room \"{}\" to {relayed_to:?} would loop back to this server",
+                    room.name
+                );
+            }
+            // Subscribe first, so listed consumers never miss a broadcast.
+            let subscription = if relayed_to.is_empty() {
+                room.subscribe()
+            } else {
+                info!("\"{id}\" relays room \"{}\" to {relayed_to:?}", room.name);
+                room.subscribe_relay(relayed_to)
+            };
+            let registration = connections.register(Role::Consumer, &id, &room.name, peer);
+            consumer::run(subscription, transport, registration, pingpong, heartbeat).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Agree on the protocol version and heartbeat, then identify the client as `(role, id, room, relayed_to)`.
+async fn handshake<T>(
+    transport: &mut T,
+    authentication: &Authentication,
+    heartbeat: Heartbeat,
+) -> anyhow::Result<(Role, String, String, Vec<String>)>
+where
+    T: Transport,
 {
     info!("Expecting protocol version message from client");
-    let Some(Ok(version)) = stream.next().await else {
-        anyhow::bail!("Failed to get protocol version message");
+    let version = match transport.receive::<ClientToServer>().await {
+        Some(Ok(ClientToServer::ProtocolVersion(version))) => version,
+        Some(Ok(other)) => {
+            handshake_failed("unexpected_message");
+            anyhow::bail!("Protocol error, expected version, got: {other:?}")
+        }
+        Some(Err(e)) => {
+            handshake_failed("decode");
+            return Err(e).context("Protocol error");
+        }
+        None => {
+            handshake_failed("closed");
+            anyhow::bail!("Failed to get protocol version message")
+        }
     };
-    let version = determine_protocol_version(&version).context("Protocol error")?;
+    info!("Client with protocol version {version}");
+
+    if version != PROTOCOL_VERSION {
+        handshake_failed("version_mismatch");
+        anyhow::bail!("Protocol version mismatch");
+    }
 
-    anyhow::ensure!(version == PROTOCOL_VERSION, "Protocol version mismatch");
+    info!("Announcing heartbeat {heartbeat:?}");
+    transport
+        .send(ServerToClient::Heartbeat(heartbeat))
+        .await
+        .inspect_err(|_| handshake_failed("closed"))
+        .context("Failed to announce heartbeat")?;
 
     info!("Expecting identification message from client");
-    let Some(Ok(identification)) = stream.next().await else {
+    let Some(identification) = transport.receive::<ClientToServer>().await else {
+        handshake_failed("closed");
         anyhow::bail!("Failed to ID");
     };
-    let Ok(text) = identification.as_text() else {
-        anyhow::bail!("Protocol error, second message wasn't a text message: {identification:?}");
+    let identification = identification
+        .inspect_err(|_| handshake_failed("decode"))
+        .context("Protocol error, client identification failed")?;
+    let (role, id, room, token, relayed_to) = match identification {
+        ClientToServer::IAmPublisher { id, room, token } => {
+            (Role::Publisher, id, room, token, Vec::new())
+        }
+        ClientToServer::IAmConsumer {
+            id,
+            room,
+            token,
+            relayed_to,
+        } => (Role::Consumer, id, room, token, relayed_to),
+        other @ ClientToServer::ProtocolVersion(_) => {
+            handshake_failed("unexpected_message");
+            anyhow::bail!("Protocol error, client identification failed: {other:?}")
+        }
     };
-    if let Ok(ClientToServer::IAmPublisher { id }) = serde_json::from_str(text) {
-        info!("Identified \"{id}\" as publisher");
-        publisher::run(chords_sender, stream, pingpong).await?;
-    } else if let Ok(ClientToServer::IAmConsumer { id }) = serde_json::from_str(text) {
-        info!("Identified \"{id}\" as consumer");
-        let chords_rx = chords_sender.subscribe();
-        consumer::run(chords_rx, stream, pingpong).await?;
-    } else {
-        anyhow::bail!("Protocol error, client identification failed: {text}");
+    let room = room.unwrap_or_else(|| morivar::DEFAULT_ROOM.to_string());
+    match authentication.identify(role, id, &room, token.as_deref()) {
+        Ok(id) => Ok((role, id, room, relayed_to)),
+        Err(e) => {
+            handshake_failed("unauthorized");
+            let _ = transport
+                .send(ServerToClient::Unauthorized(e.to_string()))
+                .await;
+            let _ = transport.close(CloseReason::PolicyViolation).await;
+            Err(e.into())
+        }
     }
-    Ok(())
 }
 
-fn determine_protocol_version(version: &Message) -> anyhow::Result<u32> {
-    let Ok(text) = version.as_text() else {
-        anyhow::bail!("Expected version, got non-text message: {version:?}");
-    };
-    let Ok(ClientToServer::ProtocolVersion(version)) = serde_json::from_str(text) else {
-        anyhow::bail!("version decoding failed: {text}");
-    };
-    info!("Client with protocol version {version}");
-    Ok(version)
+/// Await `future` for at most `timeout`, counting a handshake failure with `reason` if it takes longer.
+async fn within<F: Future>(
+    timeout: Duration,
+    reason: &str,
+    future: F,
+) -> anyhow::Result<F::Output> {
+    tokio::time::timeout(timeout, future).await.map_err(|_| {
+        handshake_failed(reason);
+        anyhow::anyhow!("Handshake did not complete within {timeout:?}")
+    })
+}
+
+fn handshake_failed(reason: &str) {
+    METRICS
+        .handshake_failures
+        .with_label_values(&[reason])
+        .inc();
 }
@@ -0,0 +1,497 @@
+use std::{collections::HashSet, time::Duration};
+
+use anyhow::Context;
+use futures_util::StreamExt;
+use klib::core::{
+    base::{HasName, Parsable},
+    chord::Chord,
+    note::Note,
+};
+use morivar::{
+    ClientToServer, ConsumerToServer, NotesDelta, NotesKeyframe, PublisherToServer, ServerToClient,
+    ServerToConsumer, ServerToPublisher, TunedNote,
+};
+use tokio::io::{split, AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+
+use crate::transport::{CloseReason, Error, Inbound, Outbound, Transport};
+
+/// The longest line a client may send, far longer than any message.
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// How a line-based client talks to quinnipak, decided by its first line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Newline-delimited JSON, same messages as over websocket
+    Json,
+    /// Human-typeable commands like `CHORD Gm9`
+    Text,
+}
+
+/// Newline-delimited messages over a raw byte stream, e.g. TCP for microcontrollers or netcat.
+pub struct LineTransport<S> {
+    /// `None` once the client sent a line which is too long
+    lines: Option<FramedRead<ReadHalf<S>, LinesCodec>>,
+    writer: WriteHalf<S>,
+    mode: Option<Mode>,
+}
+
+impl<S> LineTransport<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    pub fn new(stream: S) -> Self {
+        let (reader, writer) = split(stream);
+        Self {
+            lines: Some(FramedRead::new(
+                reader,
+                LinesCodec::new_with_max_length(MAX_LINE_LENGTH),
+            )),
+            writer,
+            mode: None,
+        }
+    }
+}
+
+impl<S> Transport for LineTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Send,
+{
+    async fn receive<M>(&mut self) -> Option<Result<M, Error>>
+    where
+        M: Inbound,
+    {
+        loop {
+            let line = match self.lines.as_mut()?.next().await? {
+                Ok(line) => line,
+                Err(LinesCodecError::MaxLineLengthExceeded) => {
+                    // Stop reading, rather than buffering whatever the client sends next.
+                    self.lines = None;
+                    let e = anyhow::anyhow!("Line longer than {MAX_LINE_LENGTH} bytes");
+                    return Some(Err(Error::Connection(e)));
+                }
+                Err(LinesCodecError::Io(e)) => return Some(Err(Error::Connection(e.into()))),
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mode = *self.mode.get_or_insert(if line.starts_with(['{', '"']) {
+                Mode::Json
+            } else {
+                Mode::Text
+            });
+            let message = match mode {
+                Mode::Json => serde_json::from_str(line).map_err(|e| Error::Decode(e.to_string())),
+                Mode::Text => M::from_command(line).map_err(|e| Error::Decode(format!("{e:#}"))),
+            };
+            return Some(message);
+        }
+    }
+
+    async fn send<M>(&mut self, message: M) -> anyhow::Result<()>
+    where
+        M: Outbound,
+    {
+        let mut line = match self.mode {
+            Some(Mode::Text) => message.to_command(),
+            Some(Mode::Json) | None => serde_json::to_string(&message)?,
+        };
+        line.push('\n');
+        self.writer
+            .write_all(line.as_bytes())
+            .await
+            .context("Failed to write line")?;
+        self.writer.flush().await.context("Failed to flush line")
+    }
+
+    /// Lines have no close codes, the client only sees the connection end.
+    async fn close(&mut self, _reason: CloseReason) -> anyhow::Result<()> {
+        self.writer
+            .shutdown()
+            .await
+            .context("Failed to shut down line connection")
+    }
+
+    /// Lines have no out-of-band ping, so there is no round trip time to measure.
+    async fn probe(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Splits a text command into its upper-cased keyword and the remaining arguments.
+fn keyword(line: &str) -> (String, &str) {
+    let (keyword, arguments) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    (keyword.to_uppercase(), arguments.trim())
+}
+
+/// Splits an optional trailing `FOR <duration>` off the arguments, like `Gm9 FOR 1s 500ms`.
+fn parse_duration(arguments: &str) -> anyhow::Result<(&str, Option<Duration>)> {
+    let Some(index) = arguments.to_ascii_lowercase().rfind(" for ") else {
+        return Ok((arguments, None));
+    };
+    let duration = arguments[index + " for ".len()..].trim();
+    let duration = humantime::parse_duration(duration)
+        .with_context(|| format!("Invalid duration \"{duration}\""))?;
+    Ok((arguments[..index].trim(), Some(duration)))
+}
+
+fn parse_pitches<'a>(notes: impl Iterator<Item = &'a str>) -> anyhow::Result<HashSet<Note>> {
+    notes
+        .map(|note| Note::parse(note).with_context(|| format!("Invalid note \"{note}\"")))
+        .collect()
+}
+
+/// Parses notes with optional cent offsets, like `A4 C5-13.7 E5+50`.
+fn parse_tuned_pitches(arguments: &str) -> anyhow::Result<Vec<TunedNote>> {
+    arguments
+        .split_whitespace()
+        .map(|word| {
+            let (note, cents) = match word.find(['+', '-']) {
+                Some(index) => word.split_at(index),
+                None => (word, "0"),
+            };
+            Ok(TunedNote {
+                note: Note::parse(note).with_context(|| format!("Invalid note \"{note}\""))?,
+                cents: parse_cents(cents)?,
+            })
+        })
+        .collect()
+}
+
+/// Parses a cent offset, which has to be finite to be sent on as JSON.
+fn parse_cents(cents: &str) -> anyhow::Result<f32> {
+    let parsed: f32 = cents
+        .parse()
+        .with_context(|| format!("Invalid cents \"{cents}\""))?;
+    anyhow::ensure!(parsed.is_finite(), "Invalid cents \"{cents}\"");
+    Ok(parsed)
+}
+
+fn format_tuned_pitches(pitches: &[TunedNote]) -> String {
+    pitches
+        .iter()
+        .map(|pitch| format!("{}{:+}", pitch.note, pitch.cents))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn parse_sequence<'a>(words: &mut impl Iterator<Item = &'a str>) -> anyhow::Result<u64> {
+    words
+        .next()
+        .context("Missing sequence number")?
+        .parse()
+        .context("Invalid sequence number")
+}
+
+/// Parses `<sequence> <previous> <note>... [VELOCITY <velocity>]`.
+fn parse_delta(arguments: &str) -> anyhow::Result<NotesDelta> {
+    let (arguments, velocity) = parse_velocity(arguments)?;
+    let mut words = arguments.split_whitespace();
+    Ok(NotesDelta {
+        sequence: parse_sequence(&mut words)?,
+        previous: parse_sequence(&mut words)?,
+        notes: parse_pitches(words)?,
+        velocity,
+    })
+}
+
+/// Splits an optional trailing `VELOCITY <velocity>` off the arguments, like `2 1 A4 VELOCITY 90`.
+fn parse_velocity(arguments: &str) -> anyhow::Result<(&str, Option<u8>)> {
+    let Some(index) = arguments.to_ascii_lowercase().rfind(" velocity ") else {
+        return Ok((arguments, None));
+    };
+    let velocity = arguments[index + " velocity ".len()..].trim();
+    let parsed = velocity
+        .parse::<u8>()
+        .ok()
+        .filter(|velocity| (1..=127).contains(velocity))
+        .with_context(|| format!("Invalid velocity \"{velocity}\""))?;
+    Ok((arguments[..index].trim(), Some(parsed)))
+}
+
+fn format_delta(delta: &NotesDelta) -> String {
+    let line = format!(
+        "{} {} {}",
+        delta.sequence,
+        delta.previous,
+        format_pitches(&delta.notes)
+    );
+    match delta.velocity {
+        Some(velocity) => format!("{line} VELOCITY {velocity}"),
+        None => line,
+    }
+}
+
+/// Parses `<sequence> <note>...`.
+fn parse_keyframe(arguments: &str) -> anyhow::Result<NotesKeyframe> {
+    let mut words = arguments.split_whitespace();
+    Ok(NotesKeyframe {
+        sequence: parse_sequence(&mut words)?,
+        notes: parse_pitches(words)?,
+    })
+}
+
+fn format_pitches(pitches: &HashSet<Note>) -> String {
+    pitches
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parses `<id> [room] [TOKEN <token>]`.
+fn parse_identification(
+    arguments: &str,
+) -> anyhow::Result<(String, Option<String>, Option<String>)> {
+    let mut arguments = arguments.split_whitespace();
+    let id = arguments.next().context("Missing id")?.to_string();
+    let mut room = None;
+    let mut token = None;
+    while let Some(argument) = arguments.next() {
+        if argument.eq_ignore_ascii_case("TOKEN") {
+            token = Some(arguments.next().context("Missing token")?.to_string());
+        } else if room.is_none() {
+            room = Some(argument.to_string());
+        } else {
+            anyhow::bail!("Unexpected argument \"{argument}\"");
+        }
+    }
+    Ok((id, room, token))
+}
+
+impl Inbound for ClientToServer {
+    fn from_command(line: &str) -> anyhow::Result<Self> {
+        let (keyword, arguments) = keyword(line);
+        match keyword.as_str() {
+            "VERSION" => Ok(Self::ProtocolVersion(
+                arguments.parse().context("Invalid protocol version")?,
+            )),
+            "PUBLISHER" => {
+                let (id, room, token) = parse_identification(arguments)?;
+                Ok(Self::IAmPublisher { id, room, token })
+            }
+            "CONSUMER" => {
+                let (id, room, token) = parse_identification(arguments)?;
+                Ok(Self::IAmConsumer {
+                    id,
+                    room,
+                    token,
+                    relayed_to: Vec::new(),
+                })
+            }
+            _ => anyhow::bail!("Expected VERSION, PUBLISHER or CONSUMER, got: {line}"),
+        }
+    }
+}
+
+impl Inbound for PublisherToServer {
+    fn from_command(line: &str) -> anyhow::Result<Self> {
+        let (keyword, arguments) = keyword(line);
+        match keyword.as_str() {
+            "CHORD" => {
+                let (chord, duration) = parse_duration(arguments)?;
+                Ok(Self::PublishChord {
+                    chord: Chord::parse(chord)
+                        .with_context(|| format!("Invalid chord \"{chord}\""))?,
+                    duration,
+                })
+            }
+            "PITCHES" => {
+                let (pitches, duration) = parse_duration(arguments)?;
+                Ok(Self::PublishPitches {
+                    pitches: parse_pitches(pitches.split_whitespace())?,
+                    duration,
+                })
+            }
+            "NOTES-ON" => Ok(Self::PublishNotesOn(parse_delta(arguments)?)),
+            "NOTES-OFF" => Ok(Self::PublishNotesOff(parse_delta(arguments)?)),
+            "KEYFRAME" => Ok(Self::PublishKeyframe(parse_keyframe(arguments)?)),
+            "TUNED" => {
+                let (pitches, duration) = parse_duration(arguments)?;
+                Ok(Self::PublishTunedPitches {
+                    pitches: parse_tuned_pitches(pitches)?,
+                    duration,
+                })
+            }
+            "BEND" => Ok(Self::PublishPitchBend {
+                cents: parse_cents(arguments)?,
+            }),
+            "SILENCE" => Ok(Self::PublishSilence),
+            "PING" => Ok(Self::Ping),
+            _ => anyhow::bail!("Unknown publisher command: {line}"),
+        }
+    }
+}
+
+impl Inbound for ConsumerToServer {
+    fn from_command(line: &str) -> anyhow::Result<Self> {
+        match keyword(line).0.as_str() {
+            "PING" => Ok(Self::Ping),
+            _ => anyhow::bail!("Expected PING, got: {line}"),
+        }
+    }
+}
+
+impl Outbound for ServerToClient {
+    fn to_command(&self) -> String {
+        match self {
+            Self::Heartbeat(heartbeat) => format!(
+                "HEARTBEAT {} {} {}",
+                humantime::format_duration(heartbeat.ping_interval),
+                humantime::format_duration(heartbeat.ping_await_interval),
+                humantime::format_duration(heartbeat.ping_to_pong_allowed_delay),
+            ),
+            Self::Unauthorized(reason) => format!("UNAUTHORIZED {reason}"),
+        }
+    }
+}
+
+impl Outbound for ServerToPublisher {
+    fn to_command(&self) -> String {
+        match self {
+            Self::Pong => "PONG".to_string(),
+            Self::NowAreYou => "NOW-ARE-YOU".to_string(),
+            Self::Error(e) => format!("ERROR {e}"),
+            Self::Redirect { address } => format!("REDIRECT {address}"),
+            Self::FloorGranted => "FLOOR GRANTED".to_string(),
+            Self::FloorLost => "FLOOR LOST".to_string(),
+            Self::RateLimited => "RATE-LIMITED".to_string(),
+        }
+    }
+}
+
+impl Outbound for ServerToConsumer {
+    fn to_command(&self) -> String {
+        match self {
+            Self::ChordEvent(chord) => format!("CHORD {}", chord.name()),
+            Self::PitchesEvent(pitches) => format!("PITCHES {}", format_pitches(pitches)),
+            Self::RecognizedPitchesEvent { pitches, chords } => format!(
+                "PITCHES {} CHORDS {}",
+                format_pitches(pitches),
+                chords
+                    .iter()
+                    .map(|chord| chord.name())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            Self::NotesOn(delta) => format!("NOTES-ON {}", format_delta(delta)),
+            Self::NotesOff(delta) => format!("NOTES-OFF {}", format_delta(delta)),
+            Self::Keyframe(keyframe) => format!(
+                "KEYFRAME {} {}",
+                keyframe.sequence,
+                format_pitches(&keyframe.notes)
+            ),
+            Self::TunedPitchesEvent(pitches) => {
+                format!("TUNED {}", format_tuned_pitches(pitches))
+            }
+            Self::PitchBendEvent { cents } => format!("BEND {cents}"),
+            Self::Silence => "SILENCE".to_string(),
+            Self::Pong => "PONG".to_string(),
+            Self::Redirect { address } => format!("REDIRECT {address}"),
+            Self::Missed { count } => format!("MISSED {count}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_identification() {
+        let Ok(ClientToServer::IAmPublisher { id, room, token }) =
+            ClientToServer::from_command("publisher netcat rehearsal")
+        else {
+            panic!("Expected publisher identification");
+        };
+        assert_eq!(id, "netcat");
+        assert_eq!(room.as_deref(), Some("rehearsal"));
+        assert_eq!(token, None);
+
+        let Ok(ClientToServer::IAmConsumer { room, token, .. }) =
+            ClientToServer::from_command("CONSUMER netcat TOKEN abc.def.ghi")
+        else {
+            panic!("Expected consumer identification");
+        };
+        assert_eq!(room, None);
+        assert_eq!(token.as_deref(), Some("abc.def.ghi"));
+    }
+
+    #[test]
+    fn parses_chord() {
+        assert!(matches!(
+            PublisherToServer::from_command("CHORD Gm9"),
+            Ok(PublisherToServer::PublishChord { duration: None, .. })
+        ));
+    }
+
+    #[test]
+    fn parses_duration() {
+        let Ok(PublisherToServer::PublishPitches { pitches, duration }) =
+            PublisherToServer::from_command("PITCHES A4 C5 for 1s 500ms")
+        else {
+            panic!("Expected pitches");
+        };
+        assert_eq!(pitches.len(), 2);
+        assert_eq!(duration, Some(Duration::from_millis(1500)));
+        assert!(PublisherToServer::from_command("CHORD C FOR ever").is_err());
+    }
+
+    #[test]
+    fn parses_delta() {
+        let Ok(PublisherToServer::PublishNotesOff(delta)) =
+            PublisherToServer::from_command("notes-off 4 3 A4")
+        else {
+            panic!("Expected notes-off delta");
+        };
+        assert_eq!(
+            (delta.sequence, delta.previous, delta.notes.len()),
+            (4, 3, 1)
+        );
+    }
+
+    #[test]
+    fn parses_delta_velocity() {
+        let Ok(PublisherToServer::PublishNotesOn(delta)) =
+            PublisherToServer::from_command("NOTES-ON 2 1 A4 velocity 90")
+        else {
+            panic!("Expected notes-on delta");
+        };
+        assert_eq!(delta.velocity, Some(90));
+        assert_eq!(
+            ServerToConsumer::NotesOn(delta).to_command(),
+            "NOTES-ON 2 1 A4 VELOCITY 90"
+        );
+        assert!(PublisherToServer::from_command("NOTES-ON 2 1 A4 VELOCITY 0").is_err());
+    }
+
+    #[test]
+    fn parses_tuned_pitches() {
+        let Ok(PublisherToServer::PublishTunedPitches { pitches, .. }) =
+            PublisherToServer::from_command("TUNED A4 E5-13.7 C5+50")
+        else {
+            panic!("Expected tuned pitches");
+        };
+        let cents = pitches.iter().map(|pitch| pitch.cents).collect::<Vec<_>>();
+        assert_eq!(cents, [0.0, -13.7, 50.0]);
+    }
+
+    #[test]
+    fn rejects_non_finite_cents() {
+        assert!(PublisherToServer::from_command("BEND -25.5").is_ok());
+        assert!(PublisherToServer::from_command("BEND nan").is_err());
+        assert!(PublisherToServer::from_command("BEND inf").is_err());
+        assert!(PublisherToServer::from_command("TUNED A4+inf").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_command() {
+        assert!(PublisherToServer::from_command("STRUM").is_err());
+        assert!(ConsumerToServer::from_command("CHORD C").is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    os::{fd::OwnedFd, unix::fs::FileTypeExt},
+    path::Path,
+};
+
+use anyhow::Context;
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio_util::either::Either;
+
+/// A connection accepted by a [`Listener`].
+pub type Stream = Either<TcpStream, UnixStream>;
+
+/// A listening TCP or Unix domain socket.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub async fn bind(address: SocketAddr) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(address)
+            .await
+            .with_context(|| format!("Failed to listen on {address}"))?;
+        Ok(Self::Tcp(listener))
+    }
+
+    /// Listen on a Unix domain socket at `path`, replacing a socket left there by an earlier run.
+    pub fn bind_unix(path: &Path) -> anyhow::Result<Self> {
+        if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Failed to remove stale socket {path:?}"))?;
+        }
+        let listener =
+            UnixListener::bind(path).with_context(|| format!("Failed to listen on {path:?}"))?;
+        Ok(Self::Unix(listener))
+    }
+
+    /// Take over a listening socket, like one passed by systemd.
+    pub fn from_fd(fd: OwnedFd) -> anyhow::Result<Self> {
+        let tcp = std::net::TcpListener::from(fd);
+        if tcp.local_addr().is_ok() {
+            tcp.set_nonblocking(true)?;
+            return Ok(Self::Tcp(TcpListener::from_std(tcp)?));
+        }
+        let unix = std::os::unix::net::UnixListener::from(OwnedFd::from(tcp));
+        unix.local_addr()
+            .context("Socket is neither a TCP nor a Unix domain socket")?;
+        unix.set_nonblocking(true)?;
+        Ok(Self::Unix(UnixListener::from_std(unix)?))
+    }
+
+    /// Accept a connection, with the peer's address if it came over TCP.
+    pub async fn accept(&self) -> io::Result<(Stream, Option<SocketAddr>)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Either::Left(stream), Some(peer)))
+            }
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Either::Right(stream), None))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(listener) => match listener.local_addr() {
+                Ok(address) => write!(f, "{address}"),
+                Err(_) => write!(f, "TCP socket"),
+            },
+            Self::Unix(listener) => match listener.local_addr() {
+                Ok(address) => match address.as_pathname() {
+                    Some(path) => write!(f, "{}", path.display()),
+                    None => write!(f, "unnamed Unix domain socket"),
+                },
+                Err(_) => write!(f, "Unix domain socket"),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn takes_over_sockets() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp.local_addr().unwrap();
+        let listener = Listener::from_fd(OwnedFd::from(tcp)).unwrap();
+        assert!(matches!(listener, Listener::Tcp(_)));
+        assert_eq!(listener.to_string(), address.to_string());
+
+        let path = std::env::temp_dir().join(format!("quinnipak-listener-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let listener = Listener::from_fd(OwnedFd::from(unix)).unwrap();
+        assert!(matches!(listener, Listener::Unix(_)));
+        // A stale socket doesn't keep quinnipak from listening again.
+        drop(listener);
+        let listener = Listener::bind_unix(&path).unwrap();
+        let _client = UnixStream::connect(&path).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,50 +1,394 @@
+use std::net::SocketAddr;
+use std::os::fd::OwnedFd;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Context;
 use clap::Parser;
-use quinnipak::quinnipak;
-use quinnipak::secure::{load_certs, load_keys};
-use quinnipak::{cli::Arguments, secure::SecurityMode};
-use tokio::{net::TcpListener, sync::broadcast};
-use tokio_rustls::TlsAcceptor;
+use quinnipak::admin::Admin;
+use quinnipak::admission::{Admission, Admitted};
+use quinnipak::auth::AuthPolicy;
+use quinnipak::cli::{Arguments, Mode};
+use quinnipak::config::{self, Settings};
+use quinnipak::connections::Connections;
+use quinnipak::listener::{Listener, Stream};
+use quinnipak::metrics::METRICS;
+use quinnipak::origin::Origins;
+use quinnipak::recorder;
+use quinnipak::relay::{self, Relay};
+use quinnipak::replay::replay;
+use quinnipak::room::Rooms;
+use quinnipak::secure::{ReloadingCertificate, Security, TlsServer};
+use quinnipak::systemd;
+use quinnipak::web::Site;
+use quinnipak::{quinnipak, quinnipak_lines};
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio_util::task::TaskTracker;
 use tracing::{info, warn};
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
+    // Clearing systemd's variables is only sound before the runtime starts its threads.
+    let systemd = systemd::Environment::take();
+    tokio::runtime::Runtime::new()?.block_on(run(systemd))
+}
+
+async fn run(mut systemd: systemd::Environment) -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
 
     let args = Arguments::parse();
+    let settings = Settings::load(&args)?;
 
-    let (chords_tx, _) = broadcast::channel(args.chords_channel_size);
-
-    info!("Listening on {:?}", args.address);
-    let listener = TcpListener::bind(args.address).await?;
+    let auth = AuthPolicy::new(
+        AuthPolicy::load_token_keys(&settings.auth.token_key_files)?,
+        false,
+        settings.auth.anonymous_consumers,
+    );
+    let mut certificates = Vec::new();
+    let security = secure(
+        settings.tls.as_ref(),
+        auth.clone(),
+        &settings.origins,
+        &mut certificates,
+    )?;
+    let listener_security = settings
+        .listeners
+        .iter()
+        .map(|listener| {
+            let mut auth = auth.clone();
+            auth.role = listener.role;
+            secure(
+                listener.tls.as_ref(),
+                auth,
+                &settings.origins,
+                &mut certificates,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
-    let acceptor = match args.mode {
+    let server_id = settings
+        .server_id
+        .clone()
+        .unwrap_or_else(relay::random_server_id);
+    let relay = match &settings.upstream {
+        Some(upstream) => Some(Arc::new(Relay::new(upstream, &server_id)?)),
         None => None,
-        Some(SecurityMode::Secure { cert, key }) => {
-            info!("Loading certificates and keys");
-            let certs = load_certs(cert)?;
-            let mut keys = load_keys(key)?;
-
-            let config = rustls::ServerConfig::builder()
-                .with_safe_defaults()
-                .with_no_client_auth()
-                .with_single_cert(certs, keys.remove(0))
-                .context("Create server config")?;
-            Some(TlsAcceptor::from(Arc::new(config)))
+    };
+
+    let recording = match &args.mode {
+        Some(Mode::Replay { file, room, speed }) => {
+            let entries = recorder::read(file)?;
+            let room = room
+                .clone()
+                .or_else(|| entries.first().map(|entry| entry.room.clone()));
+            Some((entries, room, *speed))
         }
+        _ => None,
     };
 
-    while let Ok((stream, _)) = listener.accept().await {
-        let chords_tx = chords_tx.clone();
-        let acceptor = acceptor.clone();
+    if args.check_config {
+        info!("Configuration is valid");
+        return Ok(());
+    }
+    settings.log();
+    if let Some(directory) = &settings.room_defaults.record {
+        std::fs::create_dir_all(directory)
+            .with_context(|| format!("Failed to create recording directory {directory:?}"))?;
+    }
+
+    let (runtime_tx, runtime) = watch::channel(settings.runtime.clone());
+    if args.config.is_some() {
+        let args = args.clone();
+        let settings = settings.clone();
+        tokio::spawn(async move {
+            if let Err(e) = config::reload(args, settings, runtime_tx).await {
+                warn!("Stopped reloading configuration: {e:?}");
+            }
+        });
+    }
+
+    for certificate in certificates {
+        tokio::spawn(async move {
+            if let Err(e) = certificate.watch().await {
+                warn!("Stopped reloading the TLS certificate: {e:?}");
+            }
+        });
+    }
+
+    let rooms = Rooms::new(settings.chords_channel_size)
+        .with_settings(settings.room_defaults.clone(), settings.rooms.clone())
+        .with_server_id(&server_id);
+    let connections = Connections::default();
+    let admission = Admission::new(settings.admission.clone());
+    let admin = Admin::new(rooms.clone(), connections.clone());
+    let tasks = TaskTracker::new();
 
+    if let Some((entries, room, speed)) = recording {
+        let room = rooms.join(room.as_deref());
+        info!(
+            "Replaying into room \"{}\" once a consumer joins",
+            room.name
+        );
+        tokio::spawn(replay(room, entries, speed));
+    }
+
+    if let (Some(relay), Some(upstream)) = (relay, &settings.upstream) {
+        info!("Relaying as \"{server_id}\"");
+        for name in &upstream.rooms {
+            tokio::spawn(relay.clone().run(rooms.join(Some(name))));
+        }
+    }
+
+    if let Some(address) = settings.admin_address {
+        info!("Serving admin API on {address:?}");
+        let admin_listener = TcpListener::bind(address).await?;
+        let admin = admin.clone();
         tokio::spawn(async move {
-            if let Err(e) = quinnipak(stream, chords_tx, acceptor, args.pingpong).await {
-                warn!("Error while handling connection: {e:?}");
+            if let Err(e) = admin.serve(admin_listener).await {
+                warn!("Admin API failed: {e:?}");
             }
         });
     }
+
+    let server = Server {
+        rooms,
+        connections: connections.clone(),
+        tasks: tasks.clone(),
+        runtime: runtime.clone(),
+        admission,
+        security,
+        site: settings.site.clone(),
+        configured: None,
+    };
+    let (websocket, lines) = listeners(&settings, systemd.listen_fds()?).await?;
+    for listener in websocket {
+        info!("Listening on {listener}");
+        tokio::spawn(server.clone().serve(listener));
+    }
+    for (configured, security) in settings.listeners.iter().zip(listener_security) {
+        let listener = Listener::bind(configured.address).await?;
+        info!("Listening on {listener}");
+        let server = Server {
+            security,
+            configured: Some(configured.address),
+            ..server.clone()
+        };
+        tokio::spawn(server.serve(listener));
+    }
+    for listener in lines {
+        info!("Listening for raw clients on {listener}");
+        tokio::spawn(server.clone().serve_lines(listener));
+    }
+
+    admin.set_ready(true);
+    if let Err(e) = systemd.notify("READY=1") {
+        warn!("Failed to notify systemd: {e:?}");
+    }
+
+    shutdown_signal().await?;
+    let _ = systemd.notify("STOPPING=1");
+
+    let config::Runtime {
+        drain_to,
+        shutdown_timeout,
+        ..
+    } = runtime.borrow().clone();
+    match &drain_to {
+        Some(address) => info!("Draining, redirecting clients to {address}"),
+        None => info!("Shutting down"),
+    }
+    admin.set_ready(false);
+    connections.shut_down(drain_to.map(|address| address.to_string()));
+    tasks.close();
+    if tokio::time::timeout(shutdown_timeout, tasks.wait())
+        .await
+        .is_err()
+    {
+        warn!("Clients did not leave within {shutdown_timeout:?}, exiting anyway");
+    }
+    Ok(())
+}
+
+/// Secure a websocket listener with `tls`, keeping its certificate to reload in `certificates`.
+fn secure(
+    tls: Option<&config::Tls>,
+    mut auth: AuthPolicy,
+    origins: &Origins,
+    certificates: &mut Vec<Arc<ReloadingCertificate>>,
+) -> anyhow::Result<Security> {
+    let tls = match tls {
+        None => None,
+        Some(config::Tls {
+            cert,
+            key,
+            client_ca,
+            require_client_cert_for_publishers,
+        }) => {
+            info!("Loading certificate {cert:?} and key {key:?}");
+            let certificate = Arc::new(ReloadingCertificate::load(cert, key)?);
+            let tls = TlsServer::new(certificate.clone(), client_ca.as_deref())?;
+            certificates.push(certificate);
+            auth.publisher_certificate_required = *require_client_cert_for_publishers;
+            Some(tls)
+        }
+    };
+    Ok(Security {
+        tls,
+        auth,
+        origins: origins.clone(),
+    })
+}
+
+/// The listeners for websocket clients and for raw clients.
+///
+/// Sockets passed by systemd replace the configured addresses: those named `lines` serve raw clients, others websocket clients.
+async fn listeners(
+    settings: &Settings,
+    activated: Vec<(String, OwnedFd)>,
+) -> anyhow::Result<(Vec<Listener>, Vec<Listener>)> {
+    let mut websocket = Vec::new();
+    let mut lines = Vec::new();
+    for (name, fd) in activated {
+        let listener = Listener::from_fd(fd)
+            .with_context(|| format!("Invalid socket \"{name}\" passed by systemd"))?;
+        match name.as_str() {
+            "lines" => lines.push(listener),
+            _ => websocket.push(listener),
+        }
+    }
+    if websocket.is_empty() {
+        if let Some(address) = settings.address {
+            websocket.push(Listener::bind(address).await?);
+        }
+    }
+    if let Some(path) = &settings.unix_socket {
+        websocket.push(Listener::bind_unix(path)?);
+    }
+    if lines.is_empty() {
+        if let Some(address) = settings.tcp_address {
+            lines.push(Listener::bind(address).await?);
+        }
+    }
+    if let Some(path) = &settings.lines_unix_socket {
+        lines.push(Listener::bind_unix(path)?);
+    }
+    Ok((websocket, lines))
+}
+
+/// How long to wait before accepting again after a failure.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(500);
+
+/// What each connection needs, whichever listener accepted it.
+#[derive(Clone)]
+struct Server {
+    rooms: Rooms,
+    connections: Connections,
+    tasks: TaskTracker,
+    runtime: watch::Receiver<config::Runtime>,
+    admission: Admission,
+    security: Security,
+    site: Site,
+    /// The address of the configured listener, whose heartbeat may override the runtime heartbeat
+    configured: Option<SocketAddr>,
+}
+
+impl Server {
+    /// Accept websocket clients on `listener` until shutting down.
+    async fn serve(self, listener: Listener) {
+        while let Some((stream, peer, admitted)) = self.accept(&listener).await {
+            let rooms = self.rooms.clone();
+            let connections = self.connections.clone();
+            let security = self.security.clone();
+            let site = self.site.clone();
+            let timing = {
+                let runtime = self.runtime.borrow();
+                let mut timing = runtime.timing();
+                let overridden = self
+                    .configured
+                    .and_then(|address| runtime.listener_heartbeats.get(&address));
+                if let Some(heartbeat) = overridden {
+                    timing.heartbeat = *heartbeat;
+                }
+                timing
+            };
+            self.tasks.spawn(async move {
+                let _admitted = admitted;
+                if let Err(e) =
+                    quinnipak(stream, peer, rooms, connections, security, site, timing).await
+                {
+                    warn!("Error while handling connection: {e:?}");
+                }
+            });
+        }
+    }
+
+    /// Accept raw clients on `listener` until shutting down.
+    async fn serve_lines(self, listener: Listener) {
+        while let Some((stream, peer, admitted)) = self.accept(&listener).await {
+            let rooms = self.rooms.clone();
+            let connections = self.connections.clone();
+            let auth = self.security.auth.clone();
+            let timing = self.runtime.borrow().tcp_timing();
+            self.tasks.spawn(async move {
+                let _admitted = admitted;
+                if let Err(e) =
+                    quinnipak_lines(stream, peer, rooms, connections, auth, timing).await
+                {
+                    warn!("Error while handling raw connection: {e:?}");
+                }
+            });
+        }
+    }
+
+    /// The next admitted connection, or `None` once shutting down.
+    async fn accept(&self, listener: &Listener) -> Option<(Stream, Option<SocketAddr>, Admitted)> {
+        loop {
+            let (stream, peer) = select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // Errors like running out of file descriptors pass, so keep listening.
+                        warn!("Failed to accept on {listener}, retrying in {ACCEPT_BACKOFF:?}: {e:?}");
+                        select! {
+                            () = tokio::time::sleep(ACCEPT_BACKOFF) => continue,
+                            () = self.connections.shutting_down() => return None,
+                        }
+                    }
+                },
+                () = self.connections.shutting_down() => return None,
+            };
+            if let Some(admitted) = admit(&self.admission, peer) {
+                return Some((stream, peer, admitted));
+            }
+        }
+    }
+}
+
+/// Admit a connection from `peer`, or over a Unix domain socket, logging why if it is refused.
+fn admit(admission: &Admission, peer: Option<SocketAddr>) -> Option<Admitted> {
+    let admitted = match peer {
+        Some(peer) => admission.admit(peer.ip()),
+        None => admission.admit_local(),
+    };
+    admitted
+        .inspect_err(|e| {
+            match peer {
+                Some(peer) => warn!("Refusing connection from {peer}: {e}"),
+                None => warn!("Refusing local connection: {e}"),
+            }
+            METRICS.rejected.with_label_values(&[e.reason()]).inc();
+        })
+        .ok()
+}
+
+/// Completes on SIGINT or SIGTERM.
+async fn shutdown_signal() -> anyhow::Result<()> {
+    let mut terminate = signal(SignalKind::terminate()).context("Failed to listen for SIGTERM")?;
+    select! {
+        interrupt = tokio::signal::ctrl_c() => interrupt.context("Failed to listen for SIGINT")?,
+        _ = terminate.recv() => {}
+    }
     Ok(())
 }
@@ -0,0 +1,174 @@
+use std::sync::LazyLock;
+
+use morivar::ServerToConsumer;
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGaugeVec,
+    Opts, Registry, TextEncoder,
+};
+
+/// The metrics of this server, exported on the admin API's `/metrics`.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// Connected clients by room and role
+    pub connected: IntGaugeVec,
+    /// Events broadcast to a room by event type
+    pub forwarded: IntCounterVec,
+    /// Events dropped because no consumer was subscribed
+    pub dropped: IntCounter,
+    /// Times a consumer's queue overflowed
+    pub lagged: IntCounter,
+    /// Events consumers skipped after falling behind
+    pub missed: IntCounter,
+    /// Events superseded by a later one before they were sent to a consumer
+    pub coalesced: IntCounter,
+    /// Publisher events over the publisher's rate limit
+    pub rate_limited: IntCounter,
+    /// Failed handshakes by reason
+    pub handshake_failures: IntCounterVec,
+    /// Connections refused before the handshake, by reason
+    pub rejected: IntCounterVec,
+    /// Clients cut off for not pinging, by role
+    pub watchdog_expirations: IntCounterVec,
+    /// Time from receiving an event from a publisher to sending it to a consumer
+    pub publish_to_send: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("quinnipak".to_string()), None)
+            .expect("Metrics prefix is valid");
+        let connected = IntGaugeVec::new(
+            Opts::new("connected_clients", "Connected clients"),
+            &["room", "role"],
+        )
+        .expect("Metric is valid");
+        let forwarded = IntCounterVec::new(
+            Opts::new("events_forwarded_total", "Events broadcast to a room"),
+            &["type"],
+        )
+        .expect("Metric is valid");
+        let dropped = IntCounter::new(
+            "events_dropped_total",
+            "Events dropped because no consumer was subscribed",
+        )
+        .expect("Metric is valid");
+        let lagged = IntCounter::new("broadcast_lag_total", "Times a consumer's queue overflowed")
+            .expect("Metric is valid");
+        let missed = IntCounter::new(
+            "broadcast_missed_events_total",
+            "Events consumers skipped after falling behind",
+        )
+        .expect("Metric is valid");
+        let coalesced = IntCounter::new(
+            "coalesced_events_total",
+            "Events superseded by a later one before they were sent to a consumer",
+        )
+        .expect("Metric is valid");
+        let rate_limited = IntCounter::new(
+            "rate_limited_events_total",
+            "Publisher events over the publisher's rate limit",
+        )
+        .expect("Metric is valid");
+        let handshake_failures = IntCounterVec::new(
+            Opts::new("handshake_failures_total", "Failed handshakes"),
+            &["reason"],
+        )
+        .expect("Metric is valid");
+        let rejected = IntCounterVec::new(
+            Opts::new(
+                "rejected_connections_total",
+                "Connections refused before the handshake",
+            ),
+            &["reason"],
+        )
+        .expect("Metric is valid");
+        let watchdog_expirations = IntCounterVec::new(
+            Opts::new(
+                "watchdog_expirations_total",
+                "Clients cut off for not pinging",
+            ),
+            &["role"],
+        )
+        .expect("Metric is valid");
+        let publish_to_send = Histogram::with_opts(
+            HistogramOpts::new(
+                "publish_to_send_seconds",
+                "Time from receiving an event from a publisher to sending it to a consumer",
+            )
+            .buckets(exponential_buckets(0.0001, 2.0, 16).expect("Buckets are valid")),
+        )
+        .expect("Metric is valid");
+
+        for collector in [
+            Box::new(connected.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(forwarded.clone()),
+            Box::new(dropped.clone()),
+            Box::new(lagged.clone()),
+            Box::new(missed.clone()),
+            Box::new(coalesced.clone()),
+            Box::new(rate_limited.clone()),
+            Box::new(handshake_failures.clone()),
+            Box::new(rejected.clone()),
+            Box::new(watchdog_expirations.clone()),
+            Box::new(publish_to_send.clone()),
+        ] {
+            registry
+                .register(collector)
+                .expect("Metric is registered once");
+        }
+
+        Self {
+            registry,
+            connected,
+            forwarded,
+            dropped,
+            lagged,
+            missed,
+            coalesced,
+            rate_limited,
+            handshake_failures,
+            rejected,
+            watchdog_expirations,
+            publish_to_send,
+        }
+    }
+
+    /// Render all metrics in the Prometheus text format.
+    pub fn render(&self) -> anyhow::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+/// The label for an event type on [`Metrics::forwarded`].
+pub fn event_type(message: &ServerToConsumer) -> &'static str {
+    match message {
+        ServerToConsumer::ChordEvent(_) => "chord",
+        ServerToConsumer::PitchesEvent(_) => "pitches",
+        ServerToConsumer::RecognizedPitchesEvent { .. } => "recognized_pitches",
+        ServerToConsumer::NotesOn(_) => "notes_on",
+        ServerToConsumer::NotesOff(_) => "notes_off",
+        ServerToConsumer::Keyframe(_) => "keyframe",
+        ServerToConsumer::TunedPitchesEvent(_) => "tuned_pitches",
+        ServerToConsumer::PitchBendEvent { .. } => "pitch_bend",
+        ServerToConsumer::Silence => "silence",
+        ServerToConsumer::Pong => "pong",
+        ServerToConsumer::Redirect { .. } => "redirect",
+        ServerToConsumer::Missed { .. } => "missed",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_text_format() {
+        METRICS.dropped.inc();
+        let text = METRICS.render().unwrap();
+        assert!(text.contains("# TYPE quinnipak_events_dropped_total counter"));
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::web::{self, Request, RequestError};
+
+/// The `Origin`s of browser pages allowed to connect.
+///
+/// Browsers always send an `Origin` with websocket upgrades, other clients usually don't, and are let through.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Origins {
+    /// If empty, any origin is allowed
+    pub allowed: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum OriginError {
+    #[error("Origin {0:?} is not allowed")]
+    NotAllowed(String),
+    #[error(transparent)]
+    Request(#[from] RequestError),
+}
+
+impl Origins {
+    /// Check the `Origin` of the upgrade request on `stream`, handing back the request and a stream which reads it again.
+    ///
+    /// Plain HTTP requests for the web client are let through from any origin.
+    pub async fn check<S>(&self, stream: S) -> Result<(Request, Rewind<S>), OriginError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let (request, stream) = web::read_request(stream).await?;
+        if let (true, Some(origin)) = (request.upgrade, &request.origin) {
+            if !self.allowed.is_empty() && !self.allowed.iter().any(|allowed| allowed == origin) {
+                return Err(OriginError::NotAllowed(origin.clone()));
+            }
+        }
+        Ok((request, stream))
+    }
+}
+
+/// A stream which first reads what was already read from it.
+#[derive(Debug)]
+pub struct Rewind<S> {
+    read: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub(crate) fn new(read: Vec<u8>, inner: S) -> Self {
+        Self {
+            read,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let remaining = &this.read[this.position..];
+        if remaining.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let count = remaining.len().min(buf.remaining());
+        buf.put_slice(&remaining[..count]);
+        this.position += count;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const UPGRADE: &str = "GET / HTTP/1.1\r\nHost: quinnipak\r\nOrigin: https://horeau.example\r\nUpgrade: websocket\r\n\r\n";
+
+    #[tokio::test]
+    async fn replays_allowed_request() {
+        let origins = Origins {
+            allowed: vec!["https://horeau.example".to_string()],
+        };
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(UPGRADE.as_bytes()).await.unwrap();
+        let (_, mut stream) = origins.check(server).await.unwrap();
+        let mut replayed = vec![0; UPGRADE.len()];
+        stream.read_exact(&mut replayed).await.unwrap();
+        assert_eq!(replayed, UPGRADE.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn rejects_other_origins() {
+        let origins = Origins {
+            allowed: vec!["https://elsewhere.example".to_string()],
+        };
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(UPGRADE.as_bytes()).await.unwrap();
+        assert!(matches!(
+            origins.check(server).await,
+            Err(OriginError::NotAllowed(origin)) if origin == "https://horeau.example"
+        ));
+    }
+}
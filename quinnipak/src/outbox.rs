@@ -0,0 +1,188 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use morivar::ServerToConsumer;
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::{metrics::METRICS, room::Broadcast};
+
+/// A consumer's bounded queue of events to send.
+///
+/// Events superseded by a later one are coalesced, so a slow consumer converges on what currently sounds
+/// instead of replaying stale history. Silence is only ever replaced by a later silence.
+#[derive(Debug)]
+pub struct Outbox {
+    capacity: usize,
+    queue: Mutex<VecDeque<Broadcast>>,
+    ready: Notify,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            queue: Mutex::default(),
+            ready: Notify::new(),
+        }
+    }
+
+    /// Queue `event`, replacing the queue with `current` if the consumer fell too far behind.
+    ///
+    /// `current` restores what sounds after `event`.
+    pub fn push(&self, event: Broadcast, current: impl FnOnce() -> Vec<ServerToConsumer>) {
+        let mut queue = self.queue.lock().unwrap();
+        let queued = queue.len();
+        queue.retain(|earlier| !supersedes(&event.message, &earlier.message));
+        METRICS.coalesced.inc_by((queued - queue.len()) as u64);
+        let published = event.published;
+        queue.push_back(event);
+        if queue.len() > self.capacity {
+            let mut count = 0;
+            queue.retain(|earlier| match earlier.message {
+                ServerToConsumer::Silence => true,
+                // Reported again below, with the events missed since.
+                ServerToConsumer::Missed { count: reported } => {
+                    count += reported;
+                    false
+                }
+                _ => {
+                    count += 1;
+                    false
+                }
+            });
+            warn!("Consumer fell behind, skipping {count} events");
+            METRICS.lagged.inc();
+            METRICS.missed.inc_by(count);
+            let missed = ServerToConsumer::Missed { count };
+            queue.extend(
+                std::iter::once(missed)
+                    .chain(current())
+                    .map(|message| Broadcast { message, published }),
+            );
+        }
+        self.ready.notify_one();
+    }
+
+    /// Wait for the next event to send.
+    pub async fn pop(&self) -> Broadcast {
+        loop {
+            let next = self.queue.lock().unwrap().pop_front();
+            if let Some(event) = next {
+                return event;
+            }
+            self.ready.notified().await;
+        }
+    }
+}
+
+/// Whether a consumer which has not received `earlier` yet can skip it once `later` arrives.
+fn supersedes(later: &ServerToConsumer, earlier: &ServerToConsumer) -> bool {
+    use ServerToConsumer::*;
+    let sets_notes = |message: &ServerToConsumer| {
+        matches!(
+            message,
+            ChordEvent(_)
+                | PitchesEvent(_)
+                | RecognizedPitchesEvent { .. }
+                | TunedPitchesEvent(_)
+                | Keyframe(_)
+        )
+    };
+    let changes_notes = |message: &ServerToConsumer| {
+        sets_notes(message) || matches!(message, NotesOn(_) | NotesOff(_))
+    };
+    match later {
+        Silence => changes_notes(earlier) || matches!(earlier, PitchBendEvent { .. } | Silence),
+        PitchBendEvent { .. } => matches!(earlier, PitchBendEvent { .. }),
+        later if sets_notes(later) => changes_notes(earlier),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use klib::core::{named_pitch::NamedPitch, note::Note, octave::Octave};
+    use morivar::NotesDelta;
+
+    use super::*;
+
+    fn pitches(pitch: NamedPitch) -> Broadcast {
+        ServerToConsumer::PitchesEvent(HashSet::from([Note::new(pitch, Octave::Four)])).into()
+    }
+
+    fn notes_on(sequence: u64) -> Broadcast {
+        ServerToConsumer::NotesOn(NotesDelta {
+            sequence,
+            previous: sequence - 1,
+            notes: HashSet::from([Note::new(NamedPitch::A, Octave::Four)]),
+            velocity: None,
+        })
+        .into()
+    }
+
+    async fn drain(outbox: &Outbox) -> Vec<ServerToConsumer> {
+        let mut messages = Vec::new();
+        while !outbox.queue.lock().unwrap().is_empty() {
+            messages.push(outbox.pop().await.message);
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn coalesces_superseded_events() {
+        let outbox = Outbox::new(8);
+        outbox.push(pitches(NamedPitch::C), Vec::new);
+        outbox.push(ServerToConsumer::Silence.into(), Vec::new);
+        outbox.push(pitches(NamedPitch::D), Vec::new);
+        outbox.push(
+            ServerToConsumer::PitchBendEvent { cents: 10.0 }.into(),
+            Vec::new,
+        );
+        outbox.push(pitches(NamedPitch::E), Vec::new);
+        outbox.push(
+            ServerToConsumer::PitchBendEvent { cents: 20.0 }.into(),
+            Vec::new,
+        );
+        let messages = drain(&outbox).await;
+        assert!(matches!(
+            &messages[..],
+            [
+                ServerToConsumer::Silence,
+                ServerToConsumer::PitchesEvent(pitches),
+                ServerToConsumer::PitchBendEvent { cents },
+            ] if pitches.len() == 1 && *cents == 20.0
+        ));
+    }
+
+    #[test]
+    fn stays_within_capacity_under_silence() {
+        let outbox = Outbox::new(3);
+        for _ in 0..100 {
+            outbox.push(ServerToConsumer::Silence.into(), Vec::new);
+            outbox.push(pitches(NamedPitch::C), Vec::new);
+            assert!(outbox.queue.lock().unwrap().len() <= 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_to_current_state_when_full() {
+        let outbox = Outbox::new(3);
+        outbox.push(ServerToConsumer::Silence.into(), Vec::new);
+        for sequence in 1..=3 {
+            outbox.push(notes_on(sequence), || {
+                vec![ServerToConsumer::PitchesEvent(HashSet::new())]
+            });
+        }
+        let messages = drain(&outbox).await;
+        assert!(matches!(
+            &messages[..],
+            [
+                ServerToConsumer::Silence,
+                ServerToConsumer::Missed { count: 3 },
+                ServerToConsumer::PitchesEvent(_),
+            ]
+        ));
+    }
+}
@@ -1,80 +1,331 @@
+use std::{pin::Pin, sync::Arc, time::Duration};
+
 use anyhow::Context;
-use morivar::{PublisherToServer, ServerToConsumer, ServerToPublisher, ToMessage};
+use morivar::{Heartbeat, PublisherToServer, ServerToConsumer, ServerToPublisher};
 
 use either::{Either as Response, Left as Forward, Right as ReturnToSender};
 
-use futures_util::SinkExt;
 use simple_tokio_watchdog::{Expired, Signal, Watchdog};
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
     select,
-    sync::broadcast,
+    time::{Instant, Sleep},
 };
-use tokio_websockets::{Message, WebsocketStream};
 use tracing::{info, warn};
 
-pub async fn run<S>(
-    chords_sender: broadcast::Sender<ServerToConsumer>,
-    mut stream: WebsocketStream<S>,
+use crate::{
+    connections::{Leave, Registration},
+    ensemble::Part,
+    floor::{Access, Seat},
+    metrics::METRICS,
+    rate_limit::{RateLimitAction, TokenBucket},
+    recognition,
+    recorder::Performer,
+    room::Room,
+    transport::{CloseReason, Error, Transport},
+};
+
+pub async fn run<T>(
+    room: Arc<Room>,
+    mut transport: T,
+    registration: Registration,
+    mut seat: Seat,
+    performer: Option<Performer>,
     pingpong: bool,
+    heartbeat: Heartbeat,
 ) -> anyhow::Result<()>
 where
-    S: AsyncRead + AsyncWrite + Unpin,
+    T: Transport,
 {
-    let (watchdog, mut expired) = Watchdog::with_timeout(morivar::PING_AWAIT_INTERVAL).run();
+    let (watchdog, mut expired) = Watchdog::with_timeout(heartbeat.ping_await_interval).run();
+    let mut probes = tokio::time::interval(heartbeat.ping_interval);
+    let release = tokio::time::sleep(Duration::ZERO);
+    tokio::pin!(release);
+    let mut release_pending = false;
+    let mut part = Contribution {
+        room: &room,
+        part: room
+            .ensemble
+            .as_ref()
+            .map(|ensemble| ensemble.part(registration.connection())),
+    };
+    let mut limiter = room.rate_limit.map(TokenBucket::new);
+    // The latest event over the rate limit, published once the rate allows.
+    let mut coalesced = None;
+    let refill = tokio::time::sleep(Duration::ZERO);
+    tokio::pin!(refill);
     loop {
         select! {
-            msg = stream.next() => {
+            msg = transport.receive::<PublisherToServer>() => {
                 match msg {
-                    Some(Ok(ref msg)) => {
+                    Some(Ok(msg)) => {
                         watchdog.send(Signal::Reset).await?;
+                        let release_change = release_after(&msg);
                         match handle_message(msg) {
                             Forward(consumer_message) => {
-                                if let Err(c) = chords_sender.send(consumer_message) {
-                                    warn!("Currently no subscribed consumers, dropping {:?}", c.0);
+                                if let Some(bucket) = exceeded(&mut limiter, &consumer_message) {
+                                    METRICS.rate_limited.inc();
+                                    if bucket.exceed() {
+                                        warn!("Publisher exceeded its rate limit");
+                                        transport.send(ServerToPublisher::RateLimited).await?;
+                                    }
+                                    match bucket.action() {
+                                        RateLimitAction::Coalesce if coalescable(&consumer_message) => {
+                                            refill.as_mut().reset(bucket.next_token());
+                                            coalesced = Some((consumer_message, release_change));
+                                        }
+                                        RateLimitAction::Drop | RateLimitAction::Coalesce => {}
+                                        RateLimitAction::Disconnect => {
+                                            let _ = transport.close(CloseReason::PolicyViolation).await;
+                                            anyhow::bail!("Publisher exceeded its rate limit");
+                                        }
+                                    }
+                                } else {
+                                    // Anything newer supersedes the coalesced event.
+                                    coalesced = None;
+                                    if forward(&room, &seat, &mut part, &performer, consumer_message) {
+                                        schedule(release.as_mut(), &mut release_pending, release_change);
+                                    }
                                 }
                             }
                             ReturnToSender(publisher_message) => {
-                                stream.send(publisher_message.to_message()).await?;
+                                transport.send(publisher_message).await?;
                             }
                         }
                     }
-                    Some(Err(e)) => {
+                    Some(Err(Error::Connection(e))) => {
                         warn!("Reading from stream produced error: {e:?}");
                     }
+                    Some(Err(e)) => {
+                        watchdog.send(Signal::Reset).await?;
+                        transport.send(ServerToPublisher::Error(e.to_string())).await?;
+                    }
                     None => {
                         info!("Publisher stream closed");
                         return Ok(())
                     }
                 }
             },
+            () = &mut refill, if coalesced.is_some() => {
+                if let Some((message, release_change)) = coalesced.take() {
+                    if let Some(bucket) = exceeded(&mut limiter, &message) {
+                        refill.as_mut().reset(bucket.next_token());
+                        coalesced = Some((message, release_change));
+                    } else if forward(&room, &seat, &mut part, &performer, message) {
+                        schedule(release.as_mut(), &mut release_pending, release_change);
+                    }
+                }
+            }
+            () = &mut release, if release_pending => {
+                release_pending = false;
+                if seat.holds() {
+                    info!("Duration expired, releasing");
+                    record(&performer, &ServerToConsumer::Silence);
+                    if let Err(c) = room.broadcast(part.merge(ServerToConsumer::Silence)) {
+                        warn!("Currently no subscribed consumers, dropping {c:?}");
+                    }
+                    seat.release();
+                }
+            }
+            holding = seat.changed() => {
+                if holding {
+                    info!("Publisher got the floor");
+                    transport.send(ServerToPublisher::FloorGranted).await?;
+                } else {
+                    info!("Publisher lost the floor");
+                    transport.send(ServerToPublisher::FloorLost).await?;
+                }
+            }
+            _p = probes.tick() => {
+                registration.set_rtt(transport.rtt());
+                transport.probe().await?;
+            }
+            leave = registration.leave() => {
+                info!("Publisher leaving: {leave:?}");
+                if let Leave::Redirect(address) = &leave {
+                    let address = address.clone();
+                    transport.send(ServerToPublisher::Redirect { address }).await?;
+                }
+                return transport.close(leave.close_reason()).await;
+            }
             e = &mut expired, if pingpong => {
                 let Expired = e.context("Failed to monitor watchdog")?;
+                METRICS.watchdog_expirations.with_label_values(&["publisher"]).inc();
                 anyhow::bail!("Publisher failed to ping");
             }
         }
     }
 }
 
-fn handle_message(msg: &Message) -> Response<ServerToConsumer, ServerToPublisher> {
-    let Ok(text) = msg.as_text() else {
-        return ReturnToSender(ServerToPublisher::Error(
-            "Only text messages allowed".into(),
-        ));
-    };
-    match serde_json::from_str(text) {
-        Ok(PublisherToServer::PublishChord(chord)) => {
+/// Hand an event to the room if the floor allows, returning whether it did.
+fn forward(
+    room: &Room,
+    seat: &Seat,
+    part: &mut Contribution,
+    performer: &Option<Performer>,
+    message: ServerToConsumer,
+) -> bool {
+    let access = seat.request();
+    if access == Access::Denied {
+        info!("Floor taken, dropping {message:?}");
+        return false;
+    }
+    if let Access::TookOver(previous) = access {
+        // Silence what the previous holder left sounding.
+        record(performer, &ServerToConsumer::Silence);
+        let _ = room.broadcast(part.displace(previous));
+    }
+    record(performer, &message);
+    let silence = matches!(message, ServerToConsumer::Silence);
+    let message = enrich(room, part.merge(message));
+    if let Err(c) = room.broadcast(message) {
+        warn!("Currently no subscribed consumers, dropping {c:?}");
+    }
+    if silence {
+        seat.release();
+    }
+    true
+}
+
+/// Append an accepted event to the session, if the room is recorded.
+fn record(performer: &Option<Performer>, message: &ServerToConsumer) {
+    if let Some(performer) = performer {
+        performer.record(message);
+    }
+}
+
+/// The publisher's token bucket, if `message` exceeds its rate limit.
+fn exceeded<'a>(
+    limiter: &'a mut Option<TokenBucket>,
+    message: &ServerToConsumer,
+) -> Option<&'a mut TokenBucket> {
+    // Nothing should keep sounding because of the rate limit.
+    if matches!(message, ServerToConsumer::Silence) {
+        return None;
+    }
+    let bucket = limiter.as_mut()?;
+    (!bucket.take()).then_some(bucket)
+}
+
+/// Whether only the latest of several `message`s over the rate limit matters.
+fn coalescable(message: &ServerToConsumer) -> bool {
+    matches!(
+        message,
+        ServerToConsumer::ChordEvent(_)
+            | ServerToConsumer::PitchesEvent(_)
+            | ServerToConsumer::TunedPitchesEvent(_)
+            | ServerToConsumer::Keyframe(_)
+            | ServerToConsumer::PitchBendEvent { .. }
+    )
+}
+
+/// Attach the recognized chords to `message`, if the room recognizes chords.
+fn enrich(room: &Room, message: ServerToConsumer) -> ServerToConsumer {
+    if room.recognize_chords {
+        recognition::enrich(message)
+    } else {
+        message
+    }
+}
+
+/// The publisher's part in the room's ensemble, if it plays as one, taken out when the publisher leaves.
+struct Contribution<'a> {
+    room: &'a Room,
+    part: Option<Part>,
+}
+
+impl Contribution<'_> {
+    /// The event to broadcast for `message`, merged with the other publishers' notes in an ensemble.
+    fn merge(&mut self, message: ServerToConsumer) -> ServerToConsumer {
+        match &mut self.part {
+            Some(part) => part.merge(message),
+            None => message,
+        }
+    }
+
+    /// The event to broadcast for silencing the publisher with the given connection, keeping the other parts.
+    fn displace(&self, connection: u64) -> ServerToConsumer {
+        match &self.part {
+            Some(part) => part.displace(connection),
+            None => ServerToConsumer::Silence,
+        }
+    }
+}
+
+impl Drop for Contribution<'_> {
+    fn drop(&mut self) {
+        // Stop what this publisher left sounding, the other parts keep sounding.
+        let Some(message) = self.part.take().and_then(Part::leave) else {
+            return;
+        };
+        if let Err(c) = self.room.broadcast(enrich(self.room, message)) {
+            warn!("Currently no subscribed consumers, dropping {c:?}");
+        }
+    }
+}
+
+/// What an event means for a pending automatic release.
+enum Release {
+    After(Duration),
+    Cancel,
+    Unchanged,
+}
+
+/// Apply what an event means for the pending automatic release.
+fn schedule(release: Pin<&mut Sleep>, pending: &mut bool, change: Release) {
+    match change {
+        Release::After(duration) => {
+            release.reset(Instant::now() + duration);
+            *pending = true;
+        }
+        Release::Cancel => *pending = false,
+        Release::Unchanged => {}
+    }
+}
+
+/// A new chord or pitches event supersedes the pending release, silence makes it pointless.
+fn release_after(msg: &PublisherToServer) -> Release {
+    match msg {
+        PublisherToServer::PublishChord { duration, .. }
+        | PublisherToServer::PublishPitches { duration, .. }
+        | PublisherToServer::PublishTunedPitches { duration, .. } => {
+            duration.map_or(Release::Cancel, Release::After)
+        }
+        PublisherToServer::PublishSilence => Release::Cancel,
+        _ => Release::Unchanged,
+    }
+}
+
+fn handle_message(msg: PublisherToServer) -> Response<ServerToConsumer, ServerToPublisher> {
+    match msg {
+        PublisherToServer::PublishChord { chord, .. } => {
             info!("{chord:?}");
             Forward(ServerToConsumer::ChordEvent(chord))
         }
-        Ok(PublisherToServer::PublishPitches(pitches)) => {
+        PublisherToServer::PublishPitches { pitches, .. } => {
             info!("Pitches: {pitches:?}");
             Forward(ServerToConsumer::PitchesEvent(pitches))
         }
-        Ok(PublisherToServer::PublishSilence) => Forward(ServerToConsumer::Silence),
-        Ok(PublisherToServer::Ping) => ReturnToSender(ServerToPublisher::Pong),
-        e => ReturnToSender(ServerToPublisher::Error(format!(
-            "Deserialization failed: {e:?}"
-        ))),
+        PublisherToServer::PublishNotesOn(delta) => Forward(ServerToConsumer::NotesOn(delta)),
+        PublisherToServer::PublishNotesOff(delta) => Forward(ServerToConsumer::NotesOff(delta)),
+        PublisherToServer::PublishKeyframe(keyframe) => {
+            Forward(ServerToConsumer::Keyframe(keyframe))
+        }
+        PublisherToServer::PublishTunedPitches { pitches, .. }
+            if pitches.iter().any(|pitch| !pitch.cents.is_finite()) =>
+        {
+            ReturnToSender(ServerToPublisher::Error("Cents must be finite".to_string()))
+        }
+        PublisherToServer::PublishTunedPitches { pitches, .. } => {
+            info!("Tuned pitches: {pitches:?}");
+            Forward(ServerToConsumer::TunedPitchesEvent(pitches))
+        }
+        PublisherToServer::PublishPitchBend { cents } if !cents.is_finite() => {
+            ReturnToSender(ServerToPublisher::Error("Cents must be finite".to_string()))
+        }
+        PublisherToServer::PublishPitchBend { cents } => {
+            Forward(ServerToConsumer::PitchBendEvent { cents })
+        }
+        PublisherToServer::PublishSilence => Forward(ServerToConsumer::Silence),
+        PublisherToServer::Ping => ReturnToSender(ServerToPublisher::Pong),
     }
 }
@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::time::Instant;
+
+/// How many events a publisher may publish.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// The sustained rate
+    pub events_per_second: u32,
+    /// How many events may come at once, defaults to `events_per_second`
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub action: RateLimitAction,
+}
+
+impl RateLimit {
+    fn burst(&self) -> f64 {
+        f64::from(self.burst.unwrap_or(self.events_per_second).max(1))
+    }
+}
+
+/// What to do with events over a publisher's rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitAction {
+    /// Drop them
+    #[default]
+    Drop,
+    /// Keep the latest chord, pitches or pitch bend and publish it once the rate allows, drop the others
+    Coalesce,
+    /// Disconnect the publisher
+    Disconnect,
+}
+
+/// A token bucket enforcing a [`RateLimit`] on one publisher.
+#[derive(Debug)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+    exceeded: bool,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst(),
+            refilled: Instant::now(),
+            exceeded: false,
+        }
+    }
+
+    pub fn action(&self) -> RateLimitAction {
+        self.limit.action
+    }
+
+    /// Take a token for an event, returning `false` if the rate does not allow one.
+    pub fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = (now - self.refilled).as_secs_f64() * f64::from(self.limit.events_per_second);
+        self.tokens = (self.tokens + refill).min(self.limit.burst());
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        self.exceeded = false;
+        true
+    }
+
+    /// Note an event over the limit, returning whether it is the first since the publisher kept to the limit.
+    pub fn exceed(&mut self) -> bool {
+        !std::mem::replace(&mut self.exceeded, true)
+    }
+
+    /// When the next token is available.
+    pub fn next_token(&self) -> Instant {
+        let missing = (1.0 - self.tokens).max(0.0);
+        let rate = f64::from(self.limit.events_per_second.max(1));
+        self.refilled + Duration::from_secs_f64(missing / rate)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limits_bursts() {
+        let mut bucket = TokenBucket::new(RateLimit {
+            events_per_second: 1,
+            burst: Some(2),
+            action: RateLimitAction::Drop,
+        });
+        assert!(bucket.take());
+        assert!(bucket.take());
+        assert!(!bucket.take());
+        assert!(bucket.exceed());
+        assert!(!bucket.exceed());
+        assert!(bucket.next_token() > Instant::now() + Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,56 @@
+use std::collections::HashSet;
+
+use klib::core::{chord::Chord, note::Note, pitch::HasFrequency};
+use morivar::ServerToConsumer;
+
+/// The chords `notes` could form, the best-ranked candidate first.
+pub fn recognize(notes: &HashSet<Note>) -> Vec<Chord> {
+    let mut sorted = notes.iter().copied().collect::<Vec<_>>();
+    // The lowest note is the bass.
+    sorted.sort_by(|a, b| a.frequency().total_cmp(&b.frequency()));
+    Chord::try_from_notes(&sorted).unwrap_or_default()
+}
+
+/// Attach the recognized chords to a pitches event, leaving other events as they are.
+pub fn enrich(message: ServerToConsumer) -> ServerToConsumer {
+    let ServerToConsumer::PitchesEvent(pitches) = message else {
+        return message;
+    };
+    let chords = recognize(&pitches);
+    if chords.is_empty() {
+        return ServerToConsumer::PitchesEvent(pitches);
+    }
+    ServerToConsumer::RecognizedPitchesEvent { pitches, chords }
+}
+
+#[cfg(test)]
+mod test {
+    use klib::core::{named_pitch::NamedPitch, octave::Octave};
+
+    use super::*;
+
+    #[test]
+    fn enriches_pitches_with_chords() {
+        let triad = [NamedPitch::C, NamedPitch::E, NamedPitch::G]
+            .into_iter()
+            .map(|pitch| Note::new(pitch, Octave::Four))
+            .collect::<HashSet<_>>();
+        let ServerToConsumer::RecognizedPitchesEvent { pitches, chords } =
+            enrich(ServerToConsumer::PitchesEvent(triad.clone()))
+        else {
+            panic!("Expected recognized pitches");
+        };
+        assert_eq!(pitches, triad);
+        assert!(!chords.is_empty());
+
+        let single = HashSet::from([Note::new(NamedPitch::A, Octave::Four)]);
+        assert!(matches!(
+            enrich(ServerToConsumer::PitchesEvent(single)),
+            ServerToConsumer::PitchesEvent(_)
+        ));
+        assert!(matches!(
+            enrich(ServerToConsumer::Silence),
+            ServerToConsumer::Silence
+        ));
+    }
+}
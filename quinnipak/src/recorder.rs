@@ -0,0 +1,267 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, LineWriter, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use morivar::ServerToConsumer;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// An accepted publisher event, one line of a recording.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// Milliseconds since the Unix epoch, when quinnipak accepted the event
+    pub time: u64,
+    pub room: String,
+    pub publisher: String,
+    /// Counts the events of a session, from 1
+    pub sequence: u64,
+    pub event: ServerToConsumer,
+}
+
+/// Read a recording.
+pub fn read(path: impl AsRef<Path>) -> anyhow::Result<Vec<Entry>> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("Failed to open recording {path:?}"))?;
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|(index, line)| {
+            let line = line.with_context(|| format!("Failed to read recording {path:?}"))?;
+            serde_json::from_str(&line)
+                .with_context(|| format!("Invalid event on line {} of {path:?}", index + 1))
+        })
+        .collect()
+}
+
+/// Records a room's sessions, each to its own file.
+///
+/// A session lasts while publishers are in the room. A thread of its own writes the files, so a slow disk
+/// doesn't hold up publishing.
+#[derive(Debug)]
+pub struct Recorder {
+    directory: PathBuf,
+    room: String,
+    inner: Mutex<Inner>,
+    writer: mpsc::Sender<Writing>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    publishers: usize,
+    /// The events of the current session so far, `None` between sessions
+    session: Option<u64>,
+}
+
+/// What the writer does next.
+#[derive(Debug)]
+enum Writing {
+    Start(PathBuf),
+    Record(Entry),
+    Finish,
+}
+
+#[derive(Debug)]
+struct Session {
+    path: PathBuf,
+    file: LineWriter<File>,
+    sequence: u64,
+}
+
+impl Recorder {
+    pub fn new(directory: PathBuf, room: &str) -> Self {
+        let (writer, writes) = mpsc::channel();
+        let name = room.to_string();
+        std::thread::spawn(move || write(writes, &name));
+        Self {
+            directory,
+            room: room.to_string(),
+            inner: Mutex::default(),
+            writer,
+        }
+    }
+
+    /// Count `publisher` into the current session, until the returned performer drops.
+    pub fn join(self: &Arc<Self>, publisher: &str) -> Performer {
+        self.inner.lock().unwrap().publishers += 1;
+        Performer {
+            recorder: Arc::clone(self),
+            publisher: publisher.to_string(),
+        }
+    }
+
+    fn record(&self, publisher: &str, event: &ServerToConsumer) {
+        // Sending while holding `inner` hands the writer sessions and events in order.
+        let mut inner = self.inner.lock().unwrap();
+        let sequence = inner.session.get_or_insert_with(|| {
+            let _ = self.writer.send(Writing::Start(self.session_path()));
+            0
+        });
+        *sequence += 1;
+        let entry = Entry {
+            time: now(),
+            room: self.room.clone(),
+            publisher: publisher.to_string(),
+            sequence: *sequence,
+            event: event.clone(),
+        };
+        let _ = self.writer.send(Writing::Record(entry));
+    }
+
+    /// The file for a session starting now, named after the room and the time.
+    fn session_path(&self) -> PathBuf {
+        let room: String = self
+            .room
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect();
+        let started = humantime::format_rfc3339_millis(SystemTime::now())
+            .to_string()
+            .replace(':', "");
+        self.directory.join(format!("{room}-{started}.jsonl"))
+    }
+}
+
+/// Write the sessions of `room`, until its recorder drops.
+fn write(writes: mpsc::Receiver<Writing>, room: &str) {
+    let mut session = None;
+    for writing in writes {
+        match writing {
+            Writing::Start(path) => match Session::open(path) {
+                Ok(started) => {
+                    info!("Recording room \"{room}\" to {:?}", started.path);
+                    session = Some(started);
+                }
+                Err(e) => warn!("Failed to record room \"{room}\": {e:?}"),
+            },
+            Writing::Record(entry) => {
+                // Without a file, the session's events are lost, which was reported when it started.
+                if let Some(session) = &mut session {
+                    if let Err(e) = session.append(&entry) {
+                        warn!("Failed to record event: {e:?}");
+                    }
+                }
+            }
+            Writing::Finish => {
+                if let Some(session) = session.take() {
+                    info!(
+                        "Finished recording {:?} after {} events",
+                        session.path, session.sequence
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl Session {
+    fn open(path: PathBuf) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to create recording {path:?}"))?;
+        Ok(Self {
+            path,
+            file: LineWriter::new(file),
+            sequence: 0,
+        })
+    }
+
+    fn append(&mut self, entry: &Entry) -> anyhow::Result<()> {
+        self.sequence = entry.sequence;
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.file
+            .write_all(&line)
+            .with_context(|| format!("Failed to write to recording {:?}", self.path))
+    }
+}
+
+/// A publisher taking part in a recorded session.
+#[derive(Debug)]
+pub struct Performer {
+    recorder: Arc<Recorder>,
+    publisher: String,
+}
+
+impl Performer {
+    /// Append an accepted event to the session.
+    pub fn record(&self, event: &ServerToConsumer) {
+        self.recorder.record(&self.publisher, event);
+    }
+}
+
+impl Drop for Performer {
+    fn drop(&mut self) {
+        let mut inner = self.recorder.inner.lock().unwrap();
+        inner.publishers -= 1;
+        if inner.publishers == 0 && inner.session.take().is_some() {
+            let _ = self.recorder.writer.send(Writing::Finish);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_a_file_per_session() {
+        let directory = std::env::temp_dir().join(format!("quinnipak-recorder-{}", now()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let recorder = Arc::new(Recorder::new(directory.clone(), "../choir"));
+
+        let conductor = recorder.join("conductor");
+        let soloist = recorder.join("soloist");
+        conductor.record(&ServerToConsumer::PitchBendEvent { cents: 10.0 });
+        soloist.record(&ServerToConsumer::Silence);
+        drop(conductor);
+        soloist.record(&ServerToConsumer::Silence);
+        drop(soloist);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        recorder
+            .join("conductor")
+            .record(&ServerToConsumer::Silence);
+
+        // The writer catches up on its own thread.
+        let mut files: Vec<_> = Vec::new();
+        for _ in 0..100 {
+            files = std::fs::read_dir(&directory)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect();
+            files.sort();
+            if files.len() == 2 && read(&files[1]).unwrap().len() == 1 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(files.len(), 2);
+        assert!(files[0]
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("___choir-"));
+        let entries = read(&files[0]).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].publisher, "soloist");
+        assert_eq!(entries[2].sequence, 3);
+        assert_eq!(entries[2].room, "../choir");
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use client_utils::{
+    announce_protocol_version, create_client_with_identity, create_uri, create_watchdog,
+    load_identity, receive_text, Redirected,
+};
+use futures_util::SinkExt;
+use http::uri::Authority;
+use morivar::{ClientToServer, ConsumerToServer, ServerToConsumer, ToMessage};
+use simple_tokio_watchdog::{Expired, Signal};
+use tokio::select;
+use tokio_native_tls::native_tls;
+use tracing::{info, warn};
+
+use crate::{config::Upstream, room::Room};
+
+/// How long to wait before connecting upstream again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(3);
+
+/// An id for this server, unique enough to tell relaying servers apart.
+pub fn random_server_id() -> String {
+    let random = RandomState::new().hash_one(SystemTime::now());
+    format!("quinnipak-{random:016x}")
+}
+
+/// Connects to an upstream quinnipak as a consumer, rebroadcasting its rooms here.
+pub struct Relay {
+    address: Authority,
+    secure: bool,
+    identity: Option<native_tls::Identity>,
+    token: Option<String>,
+    /// This server's id, which upstream servers check for loops
+    server_id: String,
+}
+
+/// Why a connection upstream ended.
+enum Ended {
+    Closed,
+    Redirected(Authority),
+    /// The servers this room is relayed to changed, upstream has to learn about them
+    Rejoin,
+}
+
+impl Relay {
+    /// Prepare relaying from `upstream`, reading its token and client certificate.
+    pub fn new(upstream: &Upstream, server_id: &str) -> anyhow::Result<Self> {
+        let token = match &upstream.token_file {
+            Some(path) => {
+                let token = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read upstream token {path:?}"))?;
+                Some(token.trim().to_string())
+            }
+            None => None,
+        };
+        let identity = load_identity(
+            upstream.client_cert.as_deref(),
+            upstream.client_key.as_deref(),
+        )?;
+        Ok(Self {
+            address: upstream.address.clone(),
+            secure: upstream.secure,
+            identity,
+            token,
+            server_id: server_id.to_string(),
+        })
+    }
+
+    /// Relay the upstream room of the same name into `room`, reconnecting for as long as quinnipak runs.
+    pub async fn run(self: Arc<Self>, room: Arc<Room>) {
+        let mut address = self.address.clone();
+        loop {
+            match self.relay(&address, &room).await {
+                Ok(Ended::Rejoin) => {
+                    info!(
+                        "Servers relaying room \"{}\" changed, rejoining upstream",
+                        room.name
+                    );
+                    continue;
+                }
+                Ok(Ended::Redirected(to)) => {
+                    info!("Upstream is going away to {to}");
+                    address = to;
+                }
+                Ok(Ended::Closed) => warn!("Upstream closed room \"{}\"", room.name),
+                Err(e) => warn!(
+                    "Failed to relay room \"{}\" from {address}: {e:?}",
+                    room.name
+                ),
+            }
+            // Nothing should keep sounding while the upstream is gone.
+            let _ = room.broadcast(ServerToConsumer::Silence);
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    /// Relay `room` from `address` until the connection ends.
+    async fn relay(&self, address: &Authority, room: &Room) -> anyhow::Result<Ended> {
+        let mut relayed_to = room.relayed_to();
+        let uri = create_uri(address.clone(), self.secure)?;
+        let mut stream =
+            create_client_with_identity(&uri, self.secure, self.identity.as_ref()).await?;
+        let heartbeat = announce_protocol_version(&mut stream).await?;
+
+        // Upstream refuses us if it finds itself among the servers the room would reach.
+        let downstream = relayed_to.borrow_and_update().clone();
+        let identification = ClientToServer::IAmConsumer {
+            id: self.server_id.clone(),
+            room: Some(room.name.clone()),
+            token: self.token.clone(),
+            relayed_to: std::iter::once(self.server_id.clone())
+                .chain(downstream)
+                .collect(),
+        };
+        stream
+            .send(identification.to_message())
+            .await
+            .context("Failed to identify upstream")?;
+        info!("Relaying room \"{}\" from {address}", room.name);
+
+        let (mut interval, watchdog, mut expiration) = create_watchdog(&heartbeat).await?;
+        loop {
+            select! {
+                message = receive_text(&mut stream) => {
+                    // Upstream probes with ping frames, which are skipped, only closing ends relaying.
+                    let Some(text) = message else {
+                        return Ok(Ended::Closed);
+                    };
+                    let text = text.context("Failed to receive from upstream")?;
+                    // Any message shows the upstream is alive.
+                    watchdog.send(Signal::Stop).await.context("Failed to reset the watchdog")?;
+                    let event: ServerToConsumer = serde_json::from_str(&text)
+                        .context("Protocol error, expected ServerToConsumer")?;
+                    match event {
+                        ServerToConsumer::Pong => {}
+                        ServerToConsumer::Redirect { address } => {
+                            return Ok(Ended::Redirected(Redirected::parse(&address)?.0));
+                        }
+                        ServerToConsumer::Missed { count } => {
+                            warn!("Fell behind upstream, which skipped {count} events");
+                        }
+                        event => {
+                            let _ = room.broadcast(event);
+                        }
+                    }
+                }
+                _i = interval.tick() => {
+                    watchdog.send(Signal::Reset).await.context("Failed to reset the watchdog")?;
+                    stream
+                        .send(ConsumerToServer::Ping.to_message())
+                        .await
+                        .context("Failed to ping upstream")?;
+                }
+                e = &mut expiration => {
+                    let Expired = e.context("Failed to monitor watchdog")?;
+                    anyhow::bail!("Upstream failed to pong");
+                }
+                changed = relayed_to.changed() => {
+                    changed.context("Room closed")?;
+                    let _ = stream.close(None, None).await;
+                    return Ok(Ended::Rejoin);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::{ensemble::Part, recognition, recorder::Entry, room::Room};
+
+/// Play recorded events into `room` with their original timing, `speed` times as fast.
+///
+/// Starts once a consumer is in the room. The floor and rate limits don't apply, the events were accepted when recorded.
+pub async fn replay(room: Arc<Room>, entries: Vec<Entry>, speed: f64) {
+    let Some(first) = entries.first().map(|entry| entry.time) else {
+        warn!("Nothing to replay");
+        return;
+    };
+    room.consumer_joined().await;
+    info!(
+        "Replaying {} events into room \"{}\"",
+        entries.len(),
+        room.name
+    );
+    let start = Instant::now();
+    // Recorded publishers play their own part in an ensemble, on connection ids real clients don't get.
+    let mut parts: HashMap<String, Part> = HashMap::new();
+    for entry in entries {
+        let offset = Duration::from_millis(entry.time.saturating_sub(first)).div_f64(speed);
+        tokio::time::sleep_until(start + offset).await;
+        let message = match &room.ensemble {
+            Some(ensemble) => {
+                let next = u64::MAX - parts.len() as u64;
+                parts
+                    .entry(entry.publisher)
+                    .or_insert_with(|| ensemble.part(next))
+                    .merge(entry.event)
+            }
+            None => entry.event,
+        };
+        let message = if room.recognize_chords {
+            recognition::enrich(message)
+        } else {
+            message
+        };
+        if let Err(c) = room.broadcast(message) {
+            warn!("Currently no subscribed consumers, dropping {c:?}");
+        }
+    }
+    info!("Finished replaying into room \"{}\"", room.name);
+}
+
+#[cfg(test)]
+mod test {
+    use morivar::ServerToConsumer;
+
+    use super::*;
+    use crate::room::Rooms;
+
+    fn entry(time: u64, event: ServerToConsumer) -> Entry {
+        Entry {
+            time,
+            room: "choir".to_string(),
+            publisher: "conductor".to_string(),
+            sequence: time,
+            event,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_scaled_timing() {
+        let room = Rooms::new(8).join(Some("choir"));
+        let subscription = room.subscribe();
+        let entries = vec![
+            entry(1000, ServerToConsumer::PitchBendEvent { cents: 10.0 }),
+            entry(1200, ServerToConsumer::Silence),
+        ];
+        // The clock is paused and only advances to the next timer, so the offsets are exact.
+        let start = Instant::now();
+        tokio::spawn(replay(Arc::clone(&room), entries, 2.0));
+        assert!(matches!(
+            subscription.recv().await.message,
+            ServerToConsumer::PitchBendEvent { .. }
+        ));
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert!(matches!(
+            subscription.recv().await.message,
+            ServerToConsumer::Silence
+        ));
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+    }
+}
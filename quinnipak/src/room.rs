@@ -0,0 +1,432 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use morivar::{NotesKeyframe, ServerToConsumer, SoundingNotes};
+use tokio::sync::{watch, Notify};
+use tracing::info;
+
+use crate::{
+    connections::Role,
+    ensemble::Ensemble,
+    floor::{Floor, FloorPolicy},
+    metrics::{event_type, METRICS},
+    outbox::Outbox,
+    rate_limit::RateLimit,
+    recorder::Recorder,
+    relay,
+};
+
+/// An event for a room's consumers, stamped with when it was published.
+#[derive(Debug, Clone)]
+pub struct Broadcast {
+    pub message: ServerToConsumer,
+    pub published: Instant,
+}
+
+impl From<ServerToConsumer> for Broadcast {
+    fn from(message: ServerToConsumer) -> Self {
+        Self {
+            message,
+            published: Instant::now(),
+        }
+    }
+}
+
+/// A room connects the publishers and consumers which joined it, regardless of their transport.
+#[derive(Debug)]
+pub struct Room {
+    pub name: String,
+    /// Which of the room's publishers may publish
+    pub floor: Arc<Floor>,
+    /// Merges the notes of all publishers, if the room plays as an ensemble
+    pub ensemble: Option<Arc<Ensemble>>,
+    pub recognize_chords: bool,
+    /// The rate limit of each publisher
+    pub rate_limit: Option<RateLimit>,
+    /// Records the room's sessions, if enabled
+    pub recorder: Option<Arc<Recorder>>,
+    /// What currently sounds, for consumers which fell behind
+    state: Mutex<State>,
+    /// The outbound queues of the subscribed consumers
+    consumers: Mutex<HashMap<u64, Arc<Outbox>>>,
+    /// The servers each relaying consumer forwards this room to
+    relays: Mutex<HashMap<u64, Vec<String>>>,
+    /// All servers this room is relayed to, from here on down
+    relayed_to: watch::Sender<BTreeSet<String>>,
+    subscribed: Notify,
+    next_consumer: AtomicU64,
+    queue_size: usize,
+}
+
+impl Room {
+    /// Send `message` to all consumers in this room.
+    ///
+    /// Hands `message` back if no consumer is subscribed.
+    pub fn broadcast(&self, message: ServerToConsumer) -> Result<(), ServerToConsumer> {
+        let kind = event_type(&message);
+        // Queues must see events in the same order as the state.
+        let mut state = self.state.lock().unwrap();
+        state.apply(&message);
+        let consumers = self.consumers.lock().unwrap();
+        if consumers.is_empty() {
+            METRICS.dropped.inc();
+            return Err(message);
+        }
+        let event = Broadcast::from(message);
+        for outbox in consumers.values() {
+            outbox.push(event.clone(), || state.snapshot());
+        }
+        METRICS.forwarded.with_label_values(&[kind]).inc();
+        Ok(())
+    }
+
+    /// Subscribe a consumer to this room's events, until the subscription is dropped.
+    pub fn subscribe(self: &Arc<Self>) -> Subscription {
+        let id = self.next_consumer.fetch_add(1, Ordering::Relaxed);
+        let outbox = Arc::new(Outbox::new(self.queue_size));
+        self.consumers
+            .lock()
+            .unwrap()
+            .insert(id, Arc::clone(&outbox));
+        self.subscribed.notify_waiters();
+        Subscription {
+            room: Arc::clone(self),
+            id,
+            outbox,
+        }
+    }
+
+    /// Subscribe a relaying server, which forwards this room to the servers `relayed_to`.
+    ///
+    /// The relay starts off with what currently sounds, so its own consumers catch up.
+    pub fn subscribe_relay(self: &Arc<Self>, relayed_to: Vec<String>) -> Subscription {
+        let subscription = {
+            // Holding the state keeps later events from overtaking the snapshot.
+            let state = self.state.lock().unwrap();
+            let subscription = self.subscribe();
+            for message in state.snapshot() {
+                subscription
+                    .outbox
+                    .push(Broadcast::from(message), || state.snapshot());
+            }
+            subscription
+        };
+        self.relays
+            .lock()
+            .unwrap()
+            .insert(subscription.id, relayed_to);
+        self.update_relayed_to();
+        subscription
+    }
+
+    /// All servers this room is relayed to, from here on down, as they change.
+    pub fn relayed_to(&self) -> watch::Receiver<BTreeSet<String>> {
+        self.relayed_to.subscribe()
+    }
+
+    fn update_relayed_to(&self) {
+        let relayed_to: BTreeSet<_> = self
+            .relays
+            .lock()
+            .unwrap()
+            .values()
+            .flatten()
+            .cloned()
+            .collect();
+        self.relayed_to.send_if_modified(|current| {
+            let modified = *current != relayed_to;
+            *current = relayed_to;
+            modified
+        });
+    }
+
+    /// Wait until a consumer is subscribed.
+    pub async fn consumer_joined(&self) {
+        loop {
+            let subscribed = self.subscribed.notified();
+            if !self.consumers.lock().unwrap().is_empty() {
+                return;
+            }
+            subscribed.await;
+        }
+    }
+}
+
+/// A consumer's subscription to a room.
+#[derive(Debug)]
+pub struct Subscription {
+    room: Arc<Room>,
+    id: u64,
+    outbox: Arc<Outbox>,
+}
+
+impl Subscription {
+    /// Wait for the next event to send to the consumer.
+    pub async fn recv(&self) -> Broadcast {
+        self.outbox.pop().await
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.room.consumers.lock().unwrap().remove(&self.id);
+        let relay = self.room.relays.lock().unwrap().remove(&self.id);
+        if relay.is_some() {
+            self.room.update_relayed_to();
+        }
+    }
+}
+
+/// What currently sounds in a room.
+#[derive(Debug, Default)]
+struct State {
+    /// The event for the sounding notes, `None` after silence
+    sounding: Option<ServerToConsumer>,
+    /// The notes from deltas, turned into keyframes
+    notes: SoundingNotes,
+    bend: Option<f32>,
+}
+
+impl State {
+    fn apply(&mut self, message: &ServerToConsumer) {
+        match message {
+            ServerToConsumer::ChordEvent(_)
+            | ServerToConsumer::PitchesEvent(_)
+            | ServerToConsumer::RecognizedPitchesEvent { .. }
+            | ServerToConsumer::TunedPitchesEvent(_) => {
+                self.notes.clear();
+                self.sounding = Some(message.clone());
+            }
+            ServerToConsumer::NotesOn(delta) => {
+                self.notes.notes_on(delta);
+                self.sounding = Some(self.keyframe(delta.sequence));
+            }
+            ServerToConsumer::NotesOff(delta) => {
+                self.notes.notes_off(delta);
+                self.sounding = Some(self.keyframe(delta.sequence));
+            }
+            ServerToConsumer::Keyframe(keyframe) => {
+                self.notes.keyframe(keyframe);
+                self.sounding = Some(message.clone());
+            }
+            ServerToConsumer::PitchBendEvent { cents } => self.bend = Some(*cents),
+            ServerToConsumer::Silence => *self = Self::default(),
+            ServerToConsumer::Pong
+            | ServerToConsumer::Redirect { .. }
+            | ServerToConsumer::Missed { .. } => {}
+        }
+    }
+
+    fn keyframe(&self, sequence: u64) -> ServerToConsumer {
+        ServerToConsumer::Keyframe(NotesKeyframe {
+            sequence,
+            notes: self.notes.notes().clone(),
+        })
+    }
+
+    fn snapshot(&self) -> Vec<ServerToConsumer> {
+        let sounding = self.sounding.clone().unwrap_or(ServerToConsumer::Silence);
+        let bend = self
+            .bend
+            .map(|cents| ServerToConsumer::PitchBendEvent { cents });
+        std::iter::once(sounding).chain(bend).collect()
+    }
+}
+
+/// How a room treats its publishers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoomSettings {
+    pub floor: FloorPolicy,
+    /// Broadcast the union of all publishers' sounding notes instead of each publisher's events
+    pub ensemble: bool,
+    /// Attach recognized chords to pitch events
+    pub recognize_chords: bool,
+    pub rate_limit: Option<RateLimit>,
+    /// The directory to record sessions to
+    pub record: Option<PathBuf>,
+}
+
+/// All rooms of this server, opened on first use.
+#[derive(Debug, Clone)]
+pub struct Rooms {
+    rooms: Arc<Mutex<HashMap<String, Arc<Room>>>>,
+    /// Identifies this server to relays, to detect loops
+    server_id: Arc<str>,
+    queue_size: usize,
+    defaults: Arc<RoomSettings>,
+    settings: Arc<HashMap<String, RoomSettings>>,
+}
+
+impl Rooms {
+    /// Open rooms queueing up to `queue_size` events per consumer.
+    pub fn new(queue_size: usize) -> Self {
+        Self {
+            rooms: Arc::default(),
+            server_id: relay::random_server_id().into(),
+            queue_size,
+            defaults: Arc::default(),
+            settings: Arc::default(),
+        }
+    }
+
+    /// Use the given settings for rooms by name, and `defaults` for other rooms.
+    pub fn with_settings(
+        self,
+        defaults: RoomSettings,
+        settings: HashMap<String, RoomSettings>,
+    ) -> Self {
+        Self {
+            defaults: Arc::new(defaults),
+            settings: Arc::new(settings),
+            ..self
+        }
+    }
+
+    /// Identify this server as `server_id` to relays.
+    pub fn with_server_id(self, server_id: &str) -> Self {
+        Self {
+            server_id: server_id.into(),
+            ..self
+        }
+    }
+
+    pub fn server_id(&self) -> &str {
+        &self.server_id
+    }
+
+    /// Get the room with the given name, or the default room.
+    ///
+    /// Opening a room closes the rooms nobody is in anymore, so naming rooms doesn't pile them up.
+    pub fn join(&self, name: Option<&str>) -> Arc<Room> {
+        let name = name.unwrap_or(morivar::DEFAULT_ROOM);
+        let mut rooms = self.rooms.lock().unwrap();
+        if !rooms.contains_key(name) {
+            close_empty(&mut rooms);
+        }
+        let room = rooms.entry(name.to_string()).or_insert_with(|| {
+            info!("Opening room \"{name}\"");
+            let settings = self.settings.get(name).unwrap_or(&self.defaults).clone();
+            Arc::new(Room {
+                name: name.to_string(),
+                floor: Arc::new(Floor::new(settings.floor)),
+                ensemble: settings.ensemble.then(Arc::default),
+                recognize_chords: settings.recognize_chords,
+                rate_limit: settings.rate_limit,
+                recorder: settings
+                    .record
+                    .map(|directory| Arc::new(Recorder::new(directory, name))),
+                state: Mutex::default(),
+                consumers: Mutex::default(),
+                relays: Mutex::default(),
+                relayed_to: watch::Sender::new(BTreeSet::new()),
+                subscribed: Notify::new(),
+                next_consumer: AtomicU64::default(),
+                queue_size: self.queue_size,
+            })
+        });
+        Arc::clone(room)
+    }
+
+    /// Get an open room by name.
+    pub fn get(&self, name: &str) -> Option<Arc<Room>> {
+        self.rooms.lock().unwrap().get(name).cloned()
+    }
+
+    /// All open rooms.
+    pub fn all(&self) -> Vec<Arc<Room>> {
+        self.rooms.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// Close the rooms no client, relay or replay holds on to anymore, along with their metrics.
+fn close_empty(rooms: &mut HashMap<String, Arc<Room>>) {
+    rooms.retain(|name, room| {
+        let empty = Arc::strong_count(room) == 1;
+        if empty {
+            info!("Closing empty room \"{name}\"");
+            for role in [Role::Publisher, Role::Consumer] {
+                let _ = METRICS.connected.remove_label_values(&[name, role.label()]);
+            }
+        }
+        !empty
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use klib::core::{named_pitch::NamedPitch, note::Note, octave::Octave};
+    use morivar::NotesDelta;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn subscription_receives_current_notes() {
+        let room = Rooms::new(8).join(None);
+        assert!(room.broadcast(ServerToConsumer::Silence).is_err());
+        let subscription = room.subscribe();
+        let a4 = Note::new(NamedPitch::A, Octave::Four);
+        for sequence in 1..=3 {
+            let delta = NotesDelta {
+                sequence,
+                previous: sequence - 1,
+                notes: HashSet::from([a4]),
+                velocity: None,
+            };
+            room.broadcast(ServerToConsumer::NotesOn(delta)).unwrap();
+        }
+        room.broadcast(ServerToConsumer::Keyframe(NotesKeyframe {
+            sequence: 4,
+            notes: HashSet::from([a4]),
+        }))
+        .unwrap();
+        assert!(matches!(
+            subscription.recv().await.message,
+            ServerToConsumer::Keyframe(NotesKeyframe { sequence: 4, .. })
+        ));
+        drop(subscription);
+        assert!(room.broadcast(ServerToConsumer::Silence).is_err());
+    }
+
+    #[test]
+    fn closes_empty_rooms() {
+        let rooms = Rooms::new(8);
+        let lobby = rooms.join(Some("lobby"));
+        drop(rooms.join(Some("attic")));
+        rooms.join(Some("cellar"));
+        assert!(rooms.get("attic").is_none());
+        assert!(Arc::ptr_eq(&rooms.get("lobby").unwrap(), &lobby));
+    }
+
+    #[tokio::test]
+    async fn relay_starts_with_what_sounds() {
+        let room = Rooms::new(8).join(None);
+        let relayed_to = room.relayed_to();
+        let _consumer = room.subscribe();
+        room.broadcast(ServerToConsumer::PitchBendEvent { cents: 50.0 })
+            .unwrap();
+        let relay = room.subscribe_relay(vec!["hall".to_string(), "foyer".to_string()]);
+        assert!(matches!(
+            relay.recv().await.message,
+            ServerToConsumer::Silence
+        ));
+        assert!(matches!(
+            relay.recv().await.message,
+            ServerToConsumer::PitchBendEvent { .. }
+        ));
+        assert_eq!(
+            *relayed_to.borrow(),
+            BTreeSet::from(["foyer".to_string(), "hall".to_string()])
+        );
+        drop(relay);
+        assert!(relayed_to.borrow().is_empty());
+    }
+}
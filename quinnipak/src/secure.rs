@@ -1,16 +1,40 @@
 use std::{
     fs::File,
-    io::BufReader,
+    io::{self, BufReader},
     path::{Path, PathBuf},
+    sync::{Arc, RwLock},
 };
 
 use anyhow::Context;
 use clap::Subcommand;
 
-use rustls_pemfile::{certs, pkcs8_private_keys};
-use tokio_rustls::rustls::{Certificate, PrivateKey};
+use rustls_pemfile::Item;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    select,
+    signal::unix::{signal, SignalKind},
+};
+use tokio_rustls::{
+    rustls::{
+        server::{
+            AllowAnyAnonymousOrAuthenticatedClient, ClientCertVerifier, ClientHello,
+            ResolvesServerCert,
+        },
+        sign::{any_supported_type, CertifiedKey, SigningKey},
+        Certificate, PrivateKey, RootCertStore, ServerConfig, SignatureScheme,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tracing::{info, warn};
 
-#[derive(Debug, Subcommand)]
+use crate::{
+    auth::AuthPolicy,
+    config::{modified, RELOAD_POLL_INTERVAL},
+    origin::Origins,
+};
+
+#[derive(Debug, Clone, Subcommand)]
 #[group(required = true, multiple = true)]
 pub enum SecurityMode {
     /// Use a certificate and key file for SSL-encrypted communication
@@ -20,19 +44,319 @@ pub enum SecurityMode {
 
         #[arg(short, long)]
         key: PathBuf,
+
+        /// Verify client certificates against the CA certificates in this file
+        #[arg(long)]
+        client_ca: Option<PathBuf>,
+
+        /// Only accept publishers with a verified client certificate, whose subject becomes their id
+        #[arg(long, requires = "client_ca", default_value_t = false)]
+        require_client_cert_for_publishers: bool,
     },
 }
 
-pub fn load_certs(path: impl AsRef<Path>) -> anyhow::Result<Vec<Certificate>> {
-    certs(&mut BufReader::new(File::open(path)?))
-        .context("invalid cert")
-        .map(|certs| certs.into_iter().map(Certificate).collect())
-        .context("Failed to load local certificates")
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("Failed to read {0:?}")]
+    Read(PathBuf, #[source] io::Error),
+    #[error("No certificates in {0:?}")]
+    NoCertificates(PathBuf),
+    #[error("No RSA, PKCS#8 or EC private key in {0:?}")]
+    NoKey(PathBuf),
+    #[error("More than one private key in {0:?}")]
+    MultipleKeys(PathBuf),
+    #[error("Unsupported private key type in {0:?}")]
+    UnsupportedKey(PathBuf),
+    #[error("Invalid certificate in {0:?}")]
+    InvalidCertificate(PathBuf),
+    #[error("Private key {key:?} does not belong to certificate {cert:?}")]
+    Mismatch { cert: PathBuf, key: PathBuf },
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, TlsError> {
+    let read = |e| TlsError::Read(path.to_path_buf(), e);
+    let file = File::open(path).map_err(read)?;
+    rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(read)
+}
+
+pub fn load_certs(path: impl AsRef<Path>) -> Result<Vec<Certificate>, TlsError> {
+    let path = path.as_ref();
+    let certs = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(path.to_path_buf()));
+    }
+    Ok(certs)
+}
+
+/// Load the single RSA (PKCS#1), PKCS#8 or EC (SEC1) private key in `path`.
+pub fn load_key(path: impl AsRef<Path>) -> Result<PrivateKey, TlsError> {
+    let path = path.as_ref();
+    let mut keys = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    match keys.len() {
+        0 => Err(TlsError::NoKey(path.to_path_buf())),
+        1 => Ok(keys.remove(0)),
+        _ => Err(TlsError::MultipleKeys(path.to_path_buf())),
+    }
+}
+
+/// Load a certificate chain and its private key, checking that they belong together.
+pub fn load_certified_key(
+    cert: impl AsRef<Path>,
+    key: impl AsRef<Path>,
+) -> Result<CertifiedKey, TlsError> {
+    let (cert, key) = (cert.as_ref(), key.as_ref());
+    let certs = load_certs(cert)?;
+    let signing_key =
+        any_supported_type(&load_key(key)?).map_err(|_| TlsError::UnsupportedKey(key.into()))?;
+    let end_entity = webpki::EndEntityCert::try_from(certs[0].0.as_slice())
+        .map_err(|_| TlsError::InvalidCertificate(cert.into()))?;
+    if !signs_for(signing_key.as_ref(), &end_entity) {
+        return Err(TlsError::Mismatch {
+            cert: cert.into(),
+            key: key.into(),
+        });
+    }
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// Whether the certificate verifies a signature made with the key.
+fn signs_for(key: &dyn SigningKey, cert: &webpki::EndEntityCert) -> bool {
+    const MESSAGE: &[u8] = b"quinnipak key check";
+    let algorithms = [
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &webpki::ECDSA_P256_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &webpki::ECDSA_P384_SHA384,
+        ),
+        (
+            SignatureScheme::RSA_PKCS1_SHA256,
+            &webpki::RSA_PKCS1_2048_8192_SHA256,
+        ),
+    ];
+    let Some(signer) = key.choose_scheme(&algorithms.map(|(scheme, _)| scheme)) else {
+        return false;
+    };
+    let Some((_, algorithm)) = algorithms
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+    else {
+        return false;
+    };
+    signer.sign(MESSAGE).is_ok_and(|signature| {
+        cert.verify_signature(algorithm, MESSAGE, &signature)
+            .is_ok()
+    })
+}
+
+/// Serves the certificate from its files, which can be reloaded without dropping connections.
+pub struct ReloadingCertificate {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadingCertificate {
+    pub fn load(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Result<Self, TlsError> {
+        let (cert, key) = (cert.into(), key.into());
+        let current = load_certified_key(&cert, &key)?;
+        Ok(Self {
+            cert,
+            key,
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    /// The certificate handed to new connections.
+    pub fn current(&self) -> Arc<CertifiedKey> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Load the files again, keeping the current certificate if they are invalid.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let new = load_certified_key(&self.cert, &self.key)?;
+        *self.current.write().unwrap() = Arc::new(new);
+        Ok(())
+    }
+
+    /// Reload on SIGHUP or when the certificate or key file changes.
+    pub async fn watch(&self) -> anyhow::Result<()> {
+        let mut hangup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+        let mut poll = tokio::time::interval(RELOAD_POLL_INTERVAL);
+        let files_modified = || (modified(&self.cert), modified(&self.key));
+        let mut last_modified = files_modified();
+        loop {
+            let reload = select! {
+                _ = hangup.recv() => true,
+                _ = poll.tick() => {
+                    let modified = files_modified();
+                    let changed = modified != last_modified;
+                    last_modified = modified;
+                    changed
+                }
+            };
+            if !reload {
+                continue;
+            }
+            match self.reload() {
+                Ok(()) => info!("Reloaded TLS certificate {:?}", self.cert),
+                Err(e) => warn!("Keeping the current TLS certificate: {e}"),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertificate {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+/// How a websocket listener secures and authenticates its clients.
+#[derive(Clone, Default)]
+pub struct Security {
+    pub tls: Option<TlsServer>,
+    pub auth: AuthPolicy,
+    pub origins: Origins,
+}
+
+/// Accepts TLS connections, optionally verifying client certificates.
+#[derive(Clone)]
+pub struct TlsServer {
+    acceptor: TlsAcceptor,
+}
+
+impl TlsServer {
+    pub fn new(
+        certificate: Arc<ReloadingCertificate>,
+        client_ca: Option<&Path>,
+    ) -> Result<Self, TlsError> {
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let config = match client_ca {
+            Some(path) => builder.with_client_cert_verifier(client_verifier(path)?),
+            None => builder.with_no_client_auth(),
+        }
+        .with_cert_resolver(certificate);
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    /// Complete the TLS handshake, also returning the subject of the client's verified certificate.
+    pub async fn accept<S>(&self, stream: S) -> io::Result<(TlsStream<S>, Option<String>)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let stream = self.acceptor.accept(stream).await?;
+        let certificate_subject = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(certificate_subject);
+        Ok((stream, certificate_subject))
+    }
+}
+
+/// Accept clients without a certificate, and clients with a certificate issued by a CA in `path`.
+fn client_verifier(path: &Path) -> Result<Arc<dyn ClientCertVerifier>, TlsError> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(&cert)
+            .map_err(|_| TlsError::InvalidCertificate(path.into()))?;
+    }
+    Ok(AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed())
+}
+
+/// The common name of the certificate's subject, or the whole subject if it has none.
+fn certificate_subject(cert: &Certificate) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    let subject = cert.subject();
+    let common_name = subject
+        .iter_common_name()
+        .next()
+        .and_then(|name| name.as_str().ok());
+    Some(common_name.map_or_else(|| subject.to_string(), ToString::to_string))
 }
 
-pub fn load_keys(path: impl AsRef<Path>) -> anyhow::Result<Vec<PrivateKey>> {
-    pkcs8_private_keys(&mut BufReader::new(File::open(path)?))
-        .context("invalid key")
-        .map(|keys| keys.into_iter().map(PrivateKey).collect())
-        .context("Failed to load local keys")
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn loads_rsa_pkcs8_and_ec_keys() {
+        for (cert, key) in [
+            ("rsa.crt", "rsa.key"),
+            ("ec.crt", "pkcs8.key"),
+            ("ec.crt", "ec.key"),
+        ] {
+            load_certified_key(fixture(cert), fixture(key)).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_missing_and_mismatched_keys() {
+        assert!(matches!(
+            load_certs(fixture("ec.key")),
+            Err(TlsError::NoCertificates(_))
+        ));
+        assert!(matches!(
+            load_key(fixture("ec.crt")),
+            Err(TlsError::NoKey(_))
+        ));
+        assert!(matches!(
+            load_certified_key(fixture("ec.crt"), fixture("rsa.key")),
+            Err(TlsError::Mismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn reads_certificate_subject() {
+        let certs = load_certs(fixture("ec.crt")).unwrap();
+        assert_eq!(certificate_subject(&certs[0]).unwrap(), "localhost");
+        client_verifier(&fixture("ec.crt")).unwrap();
+    }
+
+    #[test]
+    fn reloads_certificate() {
+        let dir = std::env::temp_dir().join(format!("quinnipak-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::copy(fixture("ec.crt"), &cert).unwrap();
+        std::fs::copy(fixture("ec.key"), &key).unwrap();
+        let certificate = ReloadingCertificate::load(&cert, &key).unwrap();
+        let before = certificate.current();
+
+        // Half-way through a rotation the pair does not match, so the old one stays.
+        std::fs::copy(fixture("rsa.crt"), &cert).unwrap();
+        assert!(certificate.reload().is_err());
+        assert!(Arc::ptr_eq(&before, &certificate.current()));
+
+        std::fs::copy(fixture("rsa.key"), &key).unwrap();
+        certificate.reload().unwrap();
+        assert_ne!(certificate.current().cert, before.cert);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,129 @@
+use std::{
+    env,
+    ffi::OsString,
+    io,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::{ffi::OsStrExt, net::UnixDatagram},
+    },
+    process,
+};
+
+use anyhow::Context;
+
+/// The first file descriptor systemd passes to a socket activated service.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// What systemd passes quinnipak in its environment.
+#[derive(Debug, Default)]
+pub struct Environment {
+    listen_pid: Option<String>,
+    listen_fds: Option<String>,
+    listen_fdnames: Option<String>,
+    notify_socket: Option<OsString>,
+}
+
+impl Environment {
+    /// Read and clear systemd's variables, so nothing else picks them up.
+    ///
+    /// Changing the environment races with other threads reading it, so call this before starting any.
+    pub fn take() -> Self {
+        let environment = Self {
+            listen_pid: env::var("LISTEN_PID").ok(),
+            listen_fds: env::var("LISTEN_FDS").ok(),
+            listen_fdnames: env::var("LISTEN_FDNAMES").ok(),
+            notify_socket: env::var_os("NOTIFY_SOCKET"),
+        };
+        for variable in [
+            "LISTEN_PID",
+            "LISTEN_FDS",
+            "LISTEN_FDNAMES",
+            "NOTIFY_SOCKET",
+        ] {
+            env::remove_var(variable);
+        }
+        environment
+    }
+
+    /// Take the sockets systemd passed to quinnipak, each with its `FileDescriptorName=`.
+    ///
+    /// Empty unless quinnipak was socket activated. Calling this again returns nothing instead of owning
+    /// the sockets twice.
+    pub fn listen_fds(&mut self) -> anyhow::Result<Vec<(String, OwnedFd)>> {
+        let fds = self.listen_fds.take();
+        let names = self.listen_fdnames.take().unwrap_or_default();
+        let Some(pid) = self.listen_pid.take() else {
+            return Ok(Vec::new());
+        };
+        if pid.parse::<u32>().ok() != Some(process::id()) {
+            // The sockets were meant for another process, like the shell that started quinnipak.
+            return Ok(Vec::new());
+        }
+        let count: RawFd = fds
+            .context("LISTEN_PID is set without LISTEN_FDS")?
+            .parse()
+            .context("Invalid LISTEN_FDS")?;
+        let mut names = names.split(':');
+        Ok((LISTEN_FDS_START..LISTEN_FDS_START + count)
+            .map(|fd| {
+                let name = names.next().filter(|name| !name.is_empty());
+                // systemd names sockets without `FileDescriptorName=` "unknown".
+                let name = name.unwrap_or("unknown").to_string();
+                // SAFETY: systemd passes `count` open sockets from fd 3 on, and nothing else owns them.
+                (name, unsafe { OwnedFd::from_raw_fd(fd) })
+            })
+            .collect())
+    }
+
+    /// Tell systemd about quinnipak's state, like `READY=1`, when running as a `Type=notify` service.
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        let Some(path) = &self.notify_socket else {
+            return Ok(());
+        };
+        let socket = UnixDatagram::unbound()?;
+        match path.as_bytes().strip_prefix(b"@") {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+                socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?;
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => return Err(io::ErrorKind::Unsupported.into()),
+            None => {
+                socket.send_to(state.as_bytes(), path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn notifies_the_socket() {
+        let path = env::temp_dir().join(format!("quinnipak-notify-{}", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        let environment = Environment {
+            notify_socket: Some(path.clone().into()),
+            ..Environment::default()
+        };
+        environment.notify("READY=1").unwrap();
+        let mut buffer = [0; 64];
+        let length = systemd.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ignores_sockets_for_other_processes() {
+        let mut environment = Environment {
+            listen_pid: Some((process::id() + 1).to_string()),
+            listen_fds: Some("1".to_string()),
+            ..Environment::default()
+        };
+        assert!(environment.listen_fds().unwrap().is_empty());
+    }
+}
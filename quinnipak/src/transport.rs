@@ -0,0 +1,154 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use futures_util::SinkExt;
+use morivar::ToMessage;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_websockets::{CloseCode, Message, WebsocketStream};
+
+/// A message a client may send to quinnipak.
+///
+/// Besides JSON, inbound messages can be parsed from human-typeable text commands like `CHORD Gm9`.
+pub trait Inbound: DeserializeOwned + Send {
+    fn from_command(line: &str) -> anyhow::Result<Self>;
+}
+
+/// A message quinnipak may send to a client.
+///
+/// Besides JSON, outbound messages can be rendered as human-readable text commands.
+pub trait Outbound: Serialize + Send {
+    fn to_command(&self) -> String;
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Only text messages allowed")]
+    NonText,
+
+    #[error("Deserialization failed: {0}")]
+    Decode(String),
+
+    #[error(transparent)]
+    Connection(#[from] anyhow::Error),
+}
+
+/// Why quinnipak closes a connection, sent as close code where the transport supports one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    Normal,
+    /// The server shuts down or drains
+    GoingAway,
+    /// The client was not authorized
+    PolicyViolation,
+}
+
+/// A framed, bidirectional message transport a client connection runs over.
+pub trait Transport: Send {
+    /// Receive the next message, or `None` if the client closed the connection.
+    ///
+    /// A message which could not be decoded yields [`Error::NonText`] or [`Error::Decode`],
+    /// the transport stays usable in that case.
+    fn receive<M>(&mut self) -> impl Future<Output = Option<Result<M, Error>>> + Send
+    where
+        M: Inbound;
+
+    fn send<M>(&mut self, message: M) -> impl Future<Output = anyhow::Result<()>> + Send
+    where
+        M: Outbound;
+
+    fn close(&mut self, reason: CloseReason) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Start measuring the round trip time, if the transport supports it.
+    fn probe(&mut self) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// The last measured round trip time.
+    fn rtt(&self) -> Option<Duration>;
+}
+
+/// A websocket connection, which measures its round trip time with ping frames.
+pub struct WebsocketTransport<S> {
+    stream: WebsocketStream<S>,
+    probe_sent: Option<Instant>,
+    rtt: Option<Duration>,
+}
+
+impl<S> WebsocketTransport<S> {
+    pub fn new(stream: WebsocketStream<S>) -> Self {
+        Self {
+            stream,
+            probe_sent: None,
+            rtt: None,
+        }
+    }
+}
+
+impl<S> Transport for WebsocketTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn receive<M>(&mut self) -> Option<Result<M, Error>>
+    where
+        M: Inbound,
+    {
+        loop {
+            let message = match self.stream.next().await? {
+                Ok(message) => message,
+                Err(e) => return Some(Err(Error::Connection(e.into()))),
+            };
+            if message.is_pong() {
+                if let Some(sent) = self.probe_sent.take() {
+                    self.rtt = Some(sent.elapsed());
+                }
+                continue;
+            }
+            if message.is_ping() {
+                // Answered by the websocket stream itself.
+                continue;
+            }
+            let Ok(text) = message.as_text() else {
+                return Some(Err(Error::NonText));
+            };
+            return Some(serde_json::from_str(text).map_err(|e| Error::Decode(e.to_string())));
+        }
+    }
+
+    async fn send<M>(&mut self, message: M) -> anyhow::Result<()>
+    where
+        M: Outbound,
+    {
+        self.stream
+            .send(message.to_message())
+            .await
+            .context("Failed to send message on websocket")
+    }
+
+    async fn close(&mut self, reason: CloseReason) -> anyhow::Result<()> {
+        let code = match reason {
+            CloseReason::Normal => CloseCode::NormalClosure,
+            CloseReason::GoingAway => CloseCode::GoingAway,
+            CloseReason::PolicyViolation => CloseCode::PolicyViolation,
+        };
+        self.stream
+            .close(Some(code), None)
+            .await
+            .context("Failed to close websocket")
+    }
+
+    async fn probe(&mut self) -> anyhow::Result<()> {
+        self.stream
+            .send(Message::ping(Vec::new()))
+            .await
+            .context("Failed to send ping frame")?;
+        // A probe without pong yet keeps its start, so a slow pong still counts.
+        self.probe_sent.get_or_insert_with(Instant::now);
+        Ok(())
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    borrow::Cow,
+    io,
+    path::{Component, Path, PathBuf},
+    sync::LazyLock,
+};
+
+use morivar::PROTOCOL_VERSION;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::info;
+
+use crate::origin::Rewind;
+
+/// The largest request head to read.
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+/// The page served without a site root, a minimal consumer.
+static INDEX: LazyLock<String> = LazyLock::new(|| {
+    include_str!("../web/index.html").replace("PROTOCOL_VERSION", &PROTOCOL_VERSION.to_string())
+});
+
+/// What quinnipak needs from an HTTP request head.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub origin: Option<String>,
+    /// Whether the request asks for a websocket upgrade
+    pub upgrade: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RequestError {
+    #[error("Invalid HTTP request")]
+    Invalid(#[from] httparse::Error),
+    #[error("HTTP request head is larger than {MAX_REQUEST_SIZE} bytes")]
+    TooLarge,
+    #[error("Failed to read HTTP request")]
+    Read(#[from] io::Error),
+}
+
+/// Read the head of the HTTP request on `stream`, handing back a stream which reads it again.
+pub async fn read_request<S>(mut stream: S) -> Result<(Request, Rewind<S>), RequestError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = Vec::new();
+    loop {
+        if let Some(request) = parse(&head)? {
+            return Ok((request, Rewind::new(head, stream)));
+        }
+        if head.len() >= MAX_REQUEST_SIZE {
+            return Err(RequestError::TooLarge);
+        }
+        let mut chunk = [0; 1024];
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        head.extend_from_slice(&chunk[..read]);
+    }
+}
+
+/// The request in `head`, or `None` if the head is incomplete.
+fn parse(head: &[u8]) -> Result<Option<Request>, httparse::Error> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Request::new(&mut headers);
+    if parsed.parse(head)?.is_partial() {
+        return Ok(None);
+    }
+    let header = |name: &str| {
+        parsed
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| String::from_utf8_lossy(header.value).trim().to_string())
+    };
+    Ok(Some(Request {
+        method: parsed.method.unwrap_or_default().to_string(),
+        path: parsed.path.unwrap_or("/").to_string(),
+        origin: header("origin"),
+        upgrade: header("upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket")),
+    }))
+}
+
+/// Serves the web client to plain HTTP requests.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Site {
+    /// A directory to serve instead of the built-in page, e.g. horeau's `dist`
+    pub root: Option<PathBuf>,
+}
+
+impl Site {
+    /// Answer `request` on `stream`.
+    pub async fn serve<S>(&self, request: &Request, stream: &mut S) -> io::Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let (status, content_type, body) = match request.method.as_str() {
+            "GET" | "HEAD" => self.page(&request.path).await?,
+            _ => (
+                "405 Method Not Allowed",
+                "text/plain",
+                Cow::from(&b"Method not allowed"[..]),
+            ),
+        };
+        info!("{} {}: {status}", request.method, request.path);
+        let head = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nAllow: GET, HEAD\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        if request.method != "HEAD" {
+            stream.write_all(&body).await?;
+        }
+        stream.shutdown().await
+    }
+
+    async fn page(
+        &self,
+        path: &str,
+    ) -> io::Result<(&'static str, &'static str, Cow<'static, [u8]>)> {
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        let path = match path.trim_start_matches('/') {
+            "" => "index.html",
+            path => path,
+        };
+        let not_found = Ok(("404 Not Found", "text/plain", Cow::from(&b"Not found"[..])));
+        let Some(root) = &self.root else {
+            return match path {
+                "index.html" => Ok((
+                    "200 OK",
+                    "text/html; charset=utf-8",
+                    Cow::from(INDEX.as_bytes()),
+                )),
+                _ => not_found,
+            };
+        };
+        let relative = Path::new(path);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return not_found;
+        }
+        match tokio::fs::read(root.join(relative)).await {
+            Ok(body) => Ok(("200 OK", content_type(relative), Cow::from(body))),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::IsADirectory
+                ) =>
+            {
+                not_found
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript",
+        Some("wasm") => "application/wasm",
+        Some("css") => "text/css",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const UPGRADE: &str = "GET /?room=choir HTTP/1.1\r\nHost: quinnipak\r\nOrigin: https://horeau.example\r\nUpgrade: websocket\r\n\r\n";
+
+    #[tokio::test]
+    async fn replays_the_request() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(UPGRADE.as_bytes()).await.unwrap();
+        let (request, mut stream) = read_request(server).await.unwrap();
+        assert!(request.upgrade);
+        assert_eq!(request.origin.as_deref(), Some("https://horeau.example"));
+        let mut replayed = vec![0; UPGRADE.len()];
+        stream.read_exact(&mut replayed).await.unwrap();
+        assert_eq!(replayed, UPGRADE.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn serves_the_page() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        client
+            .write_all(b"GET /?room=choir HTTP/1.1\r\nHost: quinnipak\r\n\r\n")
+            .await
+            .unwrap();
+        let (request, mut stream) = read_request(server).await.unwrap();
+        assert!(!request.upgrade);
+        Site::default().serve(&request, &mut stream).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(&format!("PublisherProtocolVersion: {PROTOCOL_VERSION}")));
+
+        let request = Request {
+            path: "/../Cargo.toml".to_string(),
+            ..request
+        };
+        let site = Site {
+            root: Some(PathBuf::from("web")),
+        };
+        let (status, ..) = site.page(&request.path).await.unwrap();
+        assert_eq!(status, "404 Not Found");
+    }
+}
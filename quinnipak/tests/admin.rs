@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use http_body_util::BodyExt;
+use quinnipak::{admin::Admin, auth::AuthPolicy, connections::Connections, room::Rooms, Timing};
+use tokio::io::{BufReader, DuplexStream, Lines};
+use tower::ServiceExt;
+
+mod common;
+
+async fn request(admin: &Admin, method: &str, uri: &str) -> (StatusCode, String) {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(Body::empty())
+        .unwrap();
+    let response = admin.clone().router().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+/// Connect a text-mode consumer over an in-memory stream, returning its incoming lines.
+async fn connect_consumer(
+    rooms: &Rooms,
+    connections: &Connections,
+    id: &str,
+    room: &str,
+) -> Lines<BufReader<DuplexStream>> {
+    let (client, _task) =
+        common::serve(rooms, connections, AuthPolicy::default(), Timing::default());
+    let lines = common::handshake(
+        client,
+        &common::text_handshake(&format!("CONSUMER {id} {room}")),
+    )
+    .await;
+    // Identification is handled after the heartbeat went out.
+    tokio::time::timeout(Duration::from_secs(1), async {
+        while connections.list().iter().all(|info| info.id != id) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .unwrap();
+    lines
+}
+
+#[tokio::test]
+async fn reports_health_and_readiness() {
+    let admin = Admin::new(Rooms::new(8), Connections::default());
+    assert_eq!(request(&admin, "GET", "/health").await.0, StatusCode::OK);
+    assert_eq!(
+        request(&admin, "GET", "/ready").await.0,
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    admin.set_ready(true);
+    assert_eq!(request(&admin, "GET", "/ready").await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn lists_connections() {
+    let rooms = Rooms::new(8);
+    let connections = Connections::default();
+    let admin = Admin::new(rooms.clone(), connections.clone());
+    let _lines = connect_consumer(&rooms, &connections, "tester", "lobby").await;
+
+    let (status, body) = request(&admin, "GET", "/connections").await;
+    assert_eq!(status, StatusCode::OK);
+    let list: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(list[0]["role"], "Consumer");
+    assert_eq!(list[0]["id"], "tester");
+    assert_eq!(list[0]["room"], "lobby");
+}
+
+#[tokio::test]
+async fn broadcasts_silence() {
+    let rooms = Rooms::new(8);
+    let connections = Connections::default();
+    let admin = Admin::new(rooms.clone(), connections.clone());
+    let mut lines = connect_consumer(&rooms, &connections, "tester", "lobby").await;
+
+    let (status, _) = request(&admin, "POST", "/rooms/lobby/silence").await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(lines.next_line().await.unwrap().unwrap(), "SILENCE");
+
+    let (status, _) = request(&admin, "POST", "/silence").await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(lines.next_line().await.unwrap().unwrap(), "SILENCE");
+
+    let (status, _) = request(&admin, "POST", "/rooms/nowhere/silence").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn kicks_client() {
+    let rooms = Rooms::new(8);
+    let connections = Connections::default();
+    let admin = Admin::new(rooms.clone(), connections.clone());
+    let mut lines = connect_consumer(&rooms, &connections, "tester", "lobby").await;
+    let connection = connections.list()[0].connection;
+
+    let (status, _) = request(&admin, "POST", &format!("/connections/{connection}/kick")).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert_eq!(lines.next_line().await.unwrap().unwrap(), "SILENCE");
+    assert_eq!(lines.next_line().await.unwrap(), None);
+
+    let (status, _) = request(&admin, "POST", "/connections/999/kick").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn exports_metrics() {
+    let rooms = Rooms::new(8);
+    let connections = Connections::default();
+    let admin = Admin::new(rooms.clone(), connections.clone());
+    let _lines = connect_consumer(&rooms, &connections, "tester", "metered").await;
+
+    let (status, body) = request(&admin, "GET", "/metrics").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(r#"quinnipak_connected_clients{role="consumer",room="metered"} 1"#));
+}
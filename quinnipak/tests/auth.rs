@@ -0,0 +1,75 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::{DecodingKey, EncodingKey, Header};
+use quinnipak::{
+    auth::{AuthPolicy, Claims},
+    connections::Connections,
+    room::Rooms,
+    Timing,
+};
+
+mod common;
+
+const SECRET: &[u8] = b"rehearsal secret";
+
+fn token(room: &str) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let claims = Claims {
+        sub: "conductor".to_string(),
+        exp: now.as_secs() + 60,
+        room: Some(room.to_string()),
+        role: None,
+    };
+    jsonwebtoken::encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(SECRET),
+    )
+    .unwrap()
+}
+
+/// Identify over an in-memory text-mode stream, returning the id the server registered or why it refused.
+async fn identify(identification: &str) -> Result<String, String> {
+    let connections = Connections::default();
+    let auth = AuthPolicy::new(vec![DecodingKey::from_secret(SECRET)], false, false);
+    let (client, task) = common::serve(&Rooms::new(8), &connections, auth, Timing::default());
+    let mut lines = common::handshake(client, &common::text_handshake(identification)).await;
+    // Rejected clients hear why and are disconnected, accepted ones hear nothing until something is published.
+    let next = tokio::time::timeout(Duration::from_millis(200), lines.next_line()).await;
+    let id = connections.list().first().map(|info| info.id.clone());
+    task.abort();
+    match (id, next) {
+        (Some(id), Err(_)) => Ok(id),
+        (None, Ok(Ok(Some(refusal)))) => {
+            let reason = refusal
+                .strip_prefix("UNAUTHORIZED ")
+                .unwrap_or_else(|| panic!("Expected a refusal, got: {refusal}"));
+            assert!(matches!(lines.next_line().await, Ok(None)));
+            Err(reason.to_string())
+        }
+        (id, next) => panic!("Registered as {id:?}, then heard {next:?}"),
+    }
+}
+
+#[tokio::test]
+async fn rejects_clients_without_valid_token() {
+    assert_eq!(
+        identify("PUBLISHER intruder choir").await,
+        Err("Client \"intruder\" presented no token".to_string())
+    );
+    let wrong_room = format!("PUBLISHER intruder band TOKEN {}", token("choir"));
+    assert_eq!(
+        identify(&wrong_room).await,
+        Err("Token is not valid for room \"band\"".to_string())
+    );
+    assert_eq!(
+        identify("CONSUMER listener choir").await,
+        Err("Client \"listener\" presented no token".to_string())
+    );
+}
+
+#[tokio::test]
+async fn identifies_client_by_token() {
+    let identification = format!("PUBLISHER anyone choir TOKEN {}", token("choir"));
+    assert_eq!(identify(&identification).await.as_deref(), Ok("conductor"));
+}
@@ -0,0 +1,48 @@
+//! Helpers shared by the integration tests, not every test uses all of them.
+#![allow(dead_code)]
+
+use morivar::PROTOCOL_VERSION;
+use quinnipak::{auth::AuthPolicy, connections::Connections, quinnipak_lines, room::Rooms, Timing};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream, Lines},
+    task::JoinHandle,
+};
+
+/// Serve a raw client over an in-memory stream, returning the client's end and the connection task.
+pub fn serve(
+    rooms: &Rooms,
+    connections: &Connections,
+    auth: AuthPolicy,
+    timing: Timing,
+) -> (DuplexStream, JoinHandle<anyhow::Result<()>>) {
+    let (client, server) = tokio::io::duplex(1024);
+    let task = tokio::spawn(quinnipak_lines(
+        server,
+        None,
+        rooms.clone(),
+        connections.clone(),
+        auth,
+        timing,
+    ));
+    (client, task)
+}
+
+/// The text-mode handshake, the protocol version followed by `identification`.
+pub fn text_handshake(identification: &str) -> String {
+    format!("VERSION {PROTOCOL_VERSION}\n{identification}\n")
+}
+
+/// Send `handshake` and read the announced heartbeat, returning the lines after it.
+pub async fn handshake(
+    mut client: DuplexStream,
+    handshake: &str,
+) -> Lines<BufReader<DuplexStream>> {
+    client.write_all(handshake.as_bytes()).await.unwrap();
+    let mut lines = BufReader::new(client).lines();
+    let heartbeat = lines.next_line().await.unwrap().unwrap();
+    assert!(
+        heartbeat.starts_with("HEARTBEAT") || heartbeat.starts_with("{\"Heartbeat\""),
+        "Expected a heartbeat, got: {heartbeat}"
+    );
+    lines
+}
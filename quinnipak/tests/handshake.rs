@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use quinnipak::{auth::AuthPolicy, connections::Connections, room::Rooms, Timing};
+use tokio::io::AsyncWriteExt;
+
+mod common;
+
+#[tokio::test]
+async fn disconnects_silent_clients() {
+    let timing = Timing {
+        handshake_timeout: Duration::from_millis(50),
+        ..Timing::default()
+    };
+    let (_client, server) = common::serve(
+        &Rooms::new(8),
+        &Connections::default(),
+        AuthPolicy::default(),
+        timing,
+    );
+    let result = tokio::time::timeout(Duration::from_secs(1), server)
+        .await
+        .expect("The handshake timeout cuts the client off")
+        .unwrap();
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn disconnects_clients_sending_endless_lines() {
+    let (mut client, server) = common::serve(
+        &Rooms::new(8),
+        &Connections::default(),
+        AuthPolicy::default(),
+        Timing::default(),
+    );
+    // Never sends a newline, which has to end the connection long before the handshake timeout.
+    let endless = vec![b'a'; 256 * 1024];
+    let _ = client.write_all(&endless).await;
+    let result = tokio::time::timeout(Duration::from_secs(1), server)
+        .await
+        .expect("The long line cuts the client off")
+        .unwrap();
+    assert!(result.is_err());
+}
@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use client_utils::{
+    announce_as_consumer, announce_protocol_version, create_client, create_uri, receive_text,
+    Identification,
+};
+use morivar::{Heartbeat, ServerToConsumer};
+use quinnipak::{
+    connections::Connections, quinnipak, room::Rooms, secure::Security, web::Site, Timing,
+};
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn clients_stay_connected_across_probes() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let rooms = Rooms::new(8);
+    let timing = Timing {
+        heartbeat: Heartbeat {
+            ping_interval: Duration::from_millis(20),
+            ..Heartbeat::default()
+        },
+        ..Timing::default()
+    };
+    let server = tokio::spawn({
+        let rooms = rooms.clone();
+        async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let site = Site::default();
+            let connections = Connections::default();
+            quinnipak(
+                stream,
+                Some(peer),
+                rooms,
+                connections,
+                Security::default(),
+                site,
+                timing,
+            )
+            .await
+        }
+    });
+
+    let uri = create_uri(address.to_string().parse().unwrap(), false).unwrap();
+    let mut stream = create_client(&uri, false).await.unwrap();
+    announce_protocol_version(&mut stream).await.unwrap();
+    let identification = Identification {
+        id: "listener".to_string(),
+        room: None,
+        token: None,
+    };
+    announce_as_consumer(&identification, &mut stream)
+        .await
+        .unwrap();
+
+    // The server probes the client a few times in the meantime.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let room = rooms.get(morivar::DEFAULT_ROOM).unwrap();
+    room.broadcast(ServerToConsumer::PitchBendEvent { cents: 50.0 })
+        .unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(1), async {
+        loop {
+            let text = receive_text(&mut stream).await.unwrap().unwrap();
+            if text.contains("PitchBendEvent") {
+                break text;
+            }
+        }
+    })
+    .await
+    .expect("The client is still connected");
+    assert_eq!(received, "{\"PitchBendEvent\":{\"cents\":50.0}}");
+    assert!(!server.is_finished());
+    server.abort();
+}
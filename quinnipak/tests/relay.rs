@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use morivar::PROTOCOL_VERSION;
+use quinnipak::{auth::AuthPolicy, connections::Connections, room::Rooms, Timing};
+use tokio::io::BufReader;
+
+mod common;
+
+/// Identify as a relay forwarding the default room to `relayed_to`, the relay itself first.
+async fn relay(
+    rooms: &Rooms,
+    relayed_to: &[&str],
+) -> (
+    tokio::io::Lines<BufReader<tokio::io::DuplexStream>>,
+    tokio::task::JoinHandle<anyhow::Result<()>>,
+) {
+    let (client, task) = common::serve(
+        rooms,
+        &Connections::default(),
+        AuthPolicy::default(),
+        Timing::default(),
+    );
+    let identification = serde_json::json!({
+        "IAmConsumer": { "id": relayed_to[0], "relayed_to": relayed_to }
+    });
+    let handshake =
+        format!("{{\"PublisherProtocolVersion\":{PROTOCOL_VERSION}}}\n{identification}\n");
+    let lines = common::handshake(client, &handshake).await;
+    (lines, task)
+}
+
+#[tokio::test]
+async fn relays_start_with_what_sounds() {
+    let rooms = Rooms::new(8).with_server_id("hall");
+    let (mut lines, task) = relay(&rooms, &["foyer", "cellar"]).await;
+    let snapshot = lines.next_line().await.unwrap().unwrap();
+    assert_eq!(snapshot, "\"Silence\"");
+    let room = rooms.get(morivar::DEFAULT_ROOM).unwrap();
+    assert!(room.relayed_to().borrow().contains("cellar"));
+    task.abort();
+}
+
+#[tokio::test]
+async fn refuses_relay_loops() {
+    let rooms = Rooms::new(8).with_server_id("hall");
+    let (mut lines, task) = relay(&rooms, &["foyer", "hall"]).await;
+    let result = tokio::time::timeout(Duration::from_secs(1), task)
+        .await
+        .expect("The loop is refused right away")
+        .unwrap();
+    assert!(result.unwrap_err().to_string().contains("loop"));
+    assert_eq!(lines.next_line().await.unwrap(), None);
+}